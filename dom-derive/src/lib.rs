@@ -0,0 +1,256 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{quote, quote_spanned};
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Expr, Fields, LitInt, Member, Path};
+
+// Declares a DOM interface, see `interface/mod.rs` in the `dom` crate.
+//
+// ```
+// #[derive(Interface)]
+// #[interface(extends(Node))]
+// #[repr(C)]
+// pub struct Element {
+//     _inherited: Node,
+// }
+// ```
+//
+// `extends` is the interface that is inherited from directly, e.g.
+// `extends(Element)`, through which the interfaces further up are inherited
+// as well. A base interface leaves it out, and can start with any field.
+// `crate` is the path to the `dom` crate, which is `::dom` by default.
+// `methods` is the table of methods of the interface, e.g. its `NodeMethods`,
+// which `Interface::register` registers along with it. `created` is the hook
+// that `Interface::into_dom` runs, which is the one of the inherited interface
+// by default.
+//
+// The ID of the interface is the hash of its fully qualified name, see
+// `InterfaceID::from_name`, unless it is given with `id`. The interface is
+// registered under its plain name though, e.g. `Element`.
+//
+// This implements `Interface`, `Inherits` for each interface that the
+// inherited one inherits from, including itself, and `Deref` and `DerefMut`
+// to the inherited interface. The layout that the casts rely on is checked at
+// compile time.
+#[proc_macro_derive(Interface, attributes(interface))]
+pub fn derive_interface(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+struct Attributes {
+    id: Option<LitInt>,
+    extends: Option<Path>,
+    krate: Path,
+    methods: Option<Expr>,
+    created: Option<Path>,
+}
+
+fn parse_attributes(input: &DeriveInput) -> syn::Result<Attributes> {
+    let mut id = None;
+    let mut extends = None;
+    let mut krate = syn::parse_quote!(::dom);
+    let mut methods = None;
+    let mut created = None;
+
+    for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("interface")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("id") {
+                let lit: LitInt = meta.value()?.parse()?;
+
+                if lit.base10_parse::<u32>()? == 0 {
+                    return Err(syn::Error::new(lit.span(), "The ID of an interface can't be 0"));
+                }
+
+                id = Some(lit);
+                Ok(())
+            } else if meta.path.is_ident("extends") {
+                meta.parse_nested_meta(|base| {
+                    if extends.is_some() {
+                        return Err(base.error("An interface only names the interface it inherits from directly"));
+                    }
+
+                    extends = Some(base.path);
+                    Ok(())
+                })
+            } else if meta.path.is_ident("crate") {
+                krate = meta.value()?.parse()?;
+                Ok(())
+            } else if meta.path.is_ident("methods") {
+                methods = Some(meta.value()?.parse()?);
+                Ok(())
+            } else if meta.path.is_ident("created") {
+                created = Some(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("Expected `id`, `extends`, `crate`, `methods` or `created`"))
+            }
+        })?;
+    }
+
+    Ok(Attributes {
+        id,
+        extends,
+        krate,
+        methods,
+        created,
+    })
+}
+
+fn is_repr_c(input: &DeriveInput) -> bool {
+    input.attrs.iter()
+        .filter(|attr| attr.path().is_ident("repr"))
+        .any(|attr| {
+            let mut c = false;
+
+            let _ = attr.parse_nested_meta(|meta| {
+                c |= meta.path.is_ident("C");
+                Ok(())
+            });
+
+            c
+        })
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let attributes = parse_attributes(&input)?;
+
+    // The casts rely on the first field being stored first.
+    if !is_repr_c(&input) {
+        return Err(syn::Error::new(input.ident.span(), "An interface MUST be `#[repr(C)]`"));
+    }
+
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => return Err(syn::Error::new(input.ident.span(), "An interface MUST be a struct")),
+    };
+
+    let first = match fields {
+        Fields::Named(fields) => fields.named.first().map(|field| (field, Member::Named(field.ident.clone().unwrap()))),
+        Fields::Unnamed(fields) => fields.unnamed.first().map(|field| (field, Member::from(0))),
+        Fields::Unit => None,
+    };
+
+    let base = attributes.extends.as_ref();
+
+    if base.is_some() && first.is_none() {
+        return Err(syn::Error::new(input.ident.span(), "An interface MUST start with the interface it inherits from"));
+    }
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let krate = &attributes.krate;
+
+    let id = match &attributes.id {
+        Some(id) => quote!(#krate::InterfaceID::new(#id)),
+        None => quote! {
+            const ID: #krate::InterfaceID = #krate::InterfaceID::from_name(
+                ::std::concat!(::std::module_path!(), "::", ::std::stringify!(#ident))
+            );
+
+            ID
+        },
+    };
+
+    // Fails to compile unless the first field is of the inherited interface.
+    let layout_check = base.zip(first.as_ref()).map(|(base, (first, member))| {
+        quote_spanned! {first.span()=>
+            const _: () = {
+                #[allow(dead_code)]
+                fn first #impl_generics (value: &#ident #ty_generics) -> &#base #where_clause {
+                    &value.#member
+                }
+            };
+        }
+    });
+
+    let base_id = match base {
+        Some(base) => quote!(::std::option::Option::Some(<#base as #krate::Interface>::id())),
+        None => quote!(::std::option::Option::None),
+    };
+
+    let register = attributes.methods.as_ref().map(|methods| {
+        quote! {
+            fn register(hier: &mut #krate::Hierarchy) -> ::std::result::Result<(), #krate::HierarchyError> {
+                hier.register_interface::<Self>()?;
+                hier.register_methods(<Self as #krate::Interface>::id(), &#methods)
+            }
+        }
+    });
+
+    let created = match (&attributes.created, base) {
+        (Some(created), _) => Some(quote! {
+            fn created(this: &#krate::Dom<Self>) {
+                #created(this)
+            }
+        }),
+        (None, Some(base)) => Some(quote! {
+            fn created(this: &#krate::Dom<Self>) {
+                let base: #krate::Dom<#base> = #krate::Upcast::upcast(#krate::Dom::clone(this));
+                <#base as #krate::Interface>::created(&base)
+            }
+        }),
+        (None, None) => None,
+    };
+
+    // The interface inherits from everything that its base inherits from,
+    // e.g. from `Node` through `Element`, whereas every interface inherits
+    // from itself already.
+    let inherits = base.map(|base| {
+        let mut generics = input.generics.clone();
+        generics.params.push(parse_quote!(__Ancestor: #krate::Interface));
+        generics.make_where_clause().predicates.push(parse_quote!(#base: #krate::Inherits<__Ancestor>));
+        let (impl_generics, _, where_clause) = generics.split_for_impl();
+
+        quote! {
+            unsafe impl #impl_generics #krate::Inherits<__Ancestor> for #ident #ty_generics #where_clause {}
+        }
+    });
+
+    let deref = base.zip(first.as_ref()).map(|(base, (_, member))| {
+        quote! {
+            impl #impl_generics ::std::ops::Deref for #ident #ty_generics #where_clause {
+                type Target = #base;
+
+                fn deref(&self) -> &Self::Target {
+                    &self.#member
+                }
+            }
+
+            impl #impl_generics ::std::ops::DerefMut for #ident #ty_generics #where_clause {
+                fn deref_mut(&mut self) -> &mut Self::Target {
+                    &mut self.#member
+                }
+            }
+        }
+    });
+
+    Ok(quote! {
+        #layout_check
+
+        impl #impl_generics #krate::Interface for #ident #ty_generics #where_clause {
+            fn id() -> #krate::InterfaceID {
+                #id
+            }
+
+            fn base() -> ::std::option::Option<#krate::InterfaceID> {
+                #base_id
+            }
+
+            fn name() -> &'static str {
+                ::std::stringify!(#ident)
+            }
+
+            #register
+
+            #created
+        }
+
+        #inherits
+
+        #deref
+    })
+}
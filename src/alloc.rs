@@ -0,0 +1,133 @@
+use std::alloc::Layout;
+use std::fmt;
+use std::ptr::NonNull;
+
+// By default the allocation of a `Dom<T>` comes from the global allocator.
+// `Dom::new_in` takes an `Allocator` instead, e.g. a pool that the nodes of a
+// document are put in so that its memory can be measured or capped.
+//
+// The allocator is stored in the allocation, after the value, so that the
+// allocation can be given back to it when the last handle is dropped, no
+// matter which interface the handle has been cast to by then.
+
+/// Hands out the memory that a `Dom<T>` lives in, see `Dom::new_in`.
+///
+/// # Safety
+///
+/// The memory that `allocate` returns MUST fit the layout, and MUST stay valid
+/// until it is given back through `deallocate`, even though the allocator is
+/// moved in the meantime. Each allocation keeps its own copy of the allocator,
+/// which is dropped right after the allocation is given back, so a pool is
+/// typically shared by cloning a handle to it.
+pub unsafe trait Allocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError>;
+
+    /// Gives back memory that was returned by `allocate`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` MUST have been returned by `allocate` on this allocator, or a
+    /// clone of it, with the same layout.
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout);
+}
+
+// Returned by an `Allocator` that is out of memory, or that doesn't want to
+// hand out any more of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllocError;
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "memory allocation failed")
+    }
+}
+
+impl std::error::Error for AllocError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Dom, WeakDom};
+    use crate::sync::Shared;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Counts the bytes that are in use, and refuses to go over a limit.
+    #[derive(Clone)]
+    struct Capped {
+        used: Shared<AtomicUsize>,
+        limit: usize,
+    }
+
+    unsafe impl Allocator for Capped {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+            if self.used.load(Ordering::SeqCst) + layout.size() > self.limit {
+                return Err(AllocError);
+            }
+
+            self.used.fetch_add(layout.size(), Ordering::SeqCst);
+            NonNull::new(unsafe { std::alloc::alloc(layout) }).ok_or(AllocError)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.used.fetch_sub(layout.size(), Ordering::SeqCst);
+            std::alloc::dealloc(ptr.as_ptr(), layout);
+        }
+    }
+
+    fn capped(limit: usize) -> Capped {
+        Capped {
+            used: Shared::new(AtomicUsize::new(0)),
+            limit,
+        }
+    }
+
+    #[test]
+    fn allocation_is_given_back() {
+        let alloc = capped(usize::MAX);
+        let dom = Dom::new_in(String::from("value"), alloc.clone());
+        let weak = Dom::downgrade(&dom);
+
+        assert_eq!(*dom.borrow(), "value");
+        assert_eq!(alloc.used.load(Ordering::SeqCst), Dom::allocation_size(&dom));
+
+        drop(dom);
+        assert!(alloc.used.load(Ordering::SeqCst) > 0);
+
+        // The allocation is kept until the weak handles are gone as well.
+        drop(weak);
+        assert_eq!(alloc.used.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn allocation_over_limit() {
+        let alloc = capped(Dom::allocation_size(&Dom::new_in(0_u64, capped(usize::MAX))));
+        let first = Dom::try_new_in(1_u64, alloc.clone()).unwrap();
+
+        assert_eq!(Dom::try_new_in(2_u64, alloc.clone()).unwrap_err(), 2);
+
+        drop(first);
+        assert!(Dom::try_new_in(3_u64, alloc).is_ok());
+    }
+
+    #[test]
+    fn try_unwrap_gives_back_allocation() {
+        let alloc = capped(usize::MAX);
+        let dom = Dom::new_in(1234_u32, alloc.clone());
+
+        assert_eq!(Dom::try_unwrap(dom).unwrap(), 1234);
+        assert_eq!(alloc.used.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn new_cyclic_in_gives_back_allocation() {
+        struct Owner(WeakDom<Owner>);
+
+        let alloc = capped(usize::MAX);
+        let dom = Dom::new_cyclic_in(|this| Owner(WeakDom::clone(this)), alloc.clone());
+
+        assert!(dom.borrow().0.upgrade().unwrap() == dom);
+
+        drop(dom);
+        assert_eq!(alloc.used.load(Ordering::SeqCst), 0);
+    }
+}
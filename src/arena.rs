@@ -0,0 +1,270 @@
+use std::alloc::Layout;
+use std::ptr::NonNull;
+
+use crate::alloc::{Allocator, AllocError};
+use crate::sync::{Lock, MaybeSync, Shared};
+use crate::{Dom, Trace};
+
+// A document can easily consist of hundreds of thousands of nodes. Allocating
+// each of them on its own scatters them all over the heap, and tearing the
+// document down means freeing them one by one.
+//
+// An `Arena` instead hands out memory from large chunks by bumping a pointer.
+// The nodes of a document end up next to each other, and the chunks are freed
+// all at once when the arena is gone.
+//
+// The values don't hold a handle to the arena. Each chunk is aligned to its
+// size and starts with a pointer to the arena instead, so that a value finds
+// its arena from its own address when it is freed. The arena is kept around
+// by its handles, e.g. the one of the document, and by a reference to itself
+// for as long as any of its values are, so a `Dom<T>` that outlives the
+// document that created it keeps the memory around until it is dropped too.
+//
+// The memory of a value that is freed is reused for the values of the same
+// size class, see `size_class`.
+// NOTE The memory of larger values isn't reused, so an arena that keeps
+//      creating and dropping them grows until it is gone.
+#[derive(Clone)]
+pub struct Arena {
+    inner: Shared<Lock<Chunks>>,
+}
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// The start of each chunk is taken up by the pointer to its arena, and padded
+// to the alignment of the size classes.
+const CHUNK_HEADER: usize = 16;
+
+// The values of up to `CLASS_SIZE * CLASSES` bytes, whose alignment is at
+// most `CLASS_SIZE`, are rounded up to a multiple of `CLASS_SIZE`, so that
+// each of them fits in the memory of any other value of the same size class.
+const CLASS_SIZE: usize = 16;
+const CLASSES: usize = 64;
+
+struct Chunks {
+    // The start and layout of every chunk, so that they can be freed.
+    chunks: Vec<(NonNull<u8>, Layout)>,
+    // The free part of the current chunk.
+    next: usize,
+    end: usize,
+    // The memory of the values that have been freed, by size class.
+    free: Vec<Vec<NonNull<u8>>>,
+    // The number of values in the arena, and the reference that keeps the
+    // arena around while there are any.
+    values: usize,
+    this: Option<Shared<Lock<Chunks>>>,
+}
+
+// SAFETY The chunks are only touched through the lock, and the memory in them
+//        is owned by the values that are allocated there.
+unsafe impl Send for Chunks {}
+
+// The allocator of the values in an arena, which only gives back their memory,
+// see `Arena::alloc`.
+struct InArena;
+
+impl Default for Arena {
+    fn default() -> Self {
+        Arena::new()
+    }
+}
+
+impl Arena {
+    pub fn new() -> Self {
+        Arena {
+            inner: Shared::new(Lock::new(Chunks {
+                chunks: Vec::new(),
+                next: 0,
+                end: 0,
+                free: (0..CLASSES).map(|_| Vec::new()).collect(),
+                values: 0,
+                this: None,
+            })),
+        }
+    }
+
+    // Creates a `Dom` whose allocation lives in the arena.
+    pub fn alloc<T: 'static>(&self, value: T) -> Dom<T> {
+        Dom::new_with(value, InArena, |layout| self.allocate(layout))
+    }
+
+    // Creates a `Dom` whose allocation lives in the arena, and whose value
+    // takes part in cycle collection, see `Dom::new_traced`.
+    pub fn alloc_traced<T: Trace + MaybeSync + 'static>(&self, value: T) -> Dom<T> {
+        Dom::new_traced_with(value, InArena, |layout| self.allocate(layout))
+    }
+
+    // The number of bytes in the chunks of the arena, used or not.
+    pub fn allocated_bytes(&self) -> usize {
+        self.inner.lock().chunks.iter()
+            .map(|(_, layout)| layout.size())
+            .sum()
+    }
+
+    // Returns uninitialized memory for the layout, which stays valid until it
+    // is given back to `InArena`.
+    fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        let mut chunks = self.inner.lock();
+        let class = size_class(layout);
+
+        let ptr = match class.and_then(|class| chunks.free[class].pop()) {
+            Some(ptr) => ptr,
+            None => {
+                let (size, align) = match class {
+                    Some(class) => ((class + 1) * CLASS_SIZE, CLASS_SIZE),
+                    None => (layout.size(), layout.align()),
+                };
+
+                chunks.bump(size, align, &self.inner)?
+            },
+        };
+
+        chunks.values += 1;
+
+        if chunks.this.is_none() {
+            chunks.this = Some(Shared::clone(&self.inner));
+        }
+
+        Ok(ptr)
+    }
+}
+
+impl Chunks {
+    fn bump(&mut self, size: usize, align: usize, arena: &Shared<Lock<Chunks>>) -> Result<NonNull<u8>, AllocError> {
+        let start = self.next.next_multiple_of(align);
+
+        if self.next != 0 && start + size <= self.end {
+            self.next = start + size;
+            return Ok(NonNull::new(start as *mut u8).unwrap());
+        }
+
+        // The value has to start in the first `CHUNK_SIZE` bytes of the chunk
+        // for it to find the arena.
+        let offset = CHUNK_HEADER.next_multiple_of(align);
+
+        if offset >= CHUNK_SIZE {
+            return Err(AllocError);
+        }
+
+        // The value doesn't fit in the current chunk, so a new one is
+        // started. A value that is larger than a chunk gets one of its own.
+        let chunk_size = (offset + size).next_multiple_of(CHUNK_SIZE);
+        let chunk = Layout::from_size_align(chunk_size, CHUNK_SIZE).map_err(|_| AllocError)?;
+        let ptr = NonNull::new(unsafe { std::alloc::alloc(chunk) }).ok_or(AllocError)?;
+
+        unsafe { ptr.cast::<*const Lock<Chunks>>().write(Shared::as_ptr(arena)); }
+
+        let start = ptr.as_ptr() as usize + offset;
+        self.chunks.push((ptr, chunk));
+        self.next = start + size;
+        self.end = ptr.as_ptr() as usize + chunk_size;
+
+        Ok(NonNull::new(start as *mut u8).unwrap())
+    }
+}
+
+// The size class of the values with the layout, if their memory is reused.
+fn size_class(layout: Layout) -> Option<usize> {
+    if layout.align() > CLASS_SIZE || layout.size() > CLASS_SIZE * CLASSES {
+        return None;
+    }
+
+    Some(layout.size().max(1).div_ceil(CLASS_SIZE) - 1)
+}
+
+// SAFETY The memory of the values stays valid for as long as they are around,
+//        since the arena keeps itself around until then.
+unsafe impl Allocator for InArena {
+    // NOTE The values are allocated by `Arena::allocate` instead.
+    fn allocate(&self, _layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        Err(AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        let chunk = (ptr.as_ptr() as usize & !(CHUNK_SIZE - 1)) as *const *const Lock<Chunks>;
+        let arena = &**chunk;
+
+        let this = {
+            let mut chunks = arena.lock();
+
+            if let Some(class) = size_class(layout) {
+                chunks.free[class].push(ptr);
+            }
+
+            chunks.values -= 1;
+
+            if chunks.values == 0 {
+                chunks.this.take()
+            } else {
+                None
+            }
+        };
+
+        // NOTE Dropped once the arena isn't locked anymore, since it might be
+        //      the last reference to it.
+        drop(this);
+    }
+}
+
+impl Drop for Chunks {
+    fn drop(&mut self) {
+        for (ptr, layout) in self.chunks.drain(..) {
+            unsafe { std::alloc::dealloc(ptr.as_ptr(), layout); }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::WeakDom;
+
+    #[test]
+    fn values_share_a_chunk() {
+        let arena = Arena::new();
+        let first = arena.alloc(1u32);
+        let second = arena.alloc(2u32);
+
+        assert_eq!(*first.borrow(), 1);
+        assert_eq!(*second.borrow(), 2);
+        assert_eq!(arena.allocated_bytes(), CHUNK_SIZE);
+    }
+
+    #[test]
+    fn large_value_gets_its_own_chunk() {
+        let arena = Arena::new();
+        let small = arena.alloc(0u8);
+        let large = arena.alloc([7u8; CHUNK_SIZE]);
+
+        assert_eq!(*small.borrow(), 0);
+        assert!(large.borrow().iter().all(|&byte| byte == 7));
+        assert!(arena.allocated_bytes() > 2 * CHUNK_SIZE);
+    }
+
+    #[test]
+    fn memory_of_freed_values_is_reused() {
+        let arena = Arena::new();
+        let kept = arena.alloc(0u64);
+
+        for i in 0..100_000u64 {
+            let value = arena.alloc(i);
+            assert_eq!(*value.borrow(), i);
+        }
+
+        assert_eq!(*kept.borrow(), 0);
+        assert_eq!(arena.allocated_bytes(), CHUNK_SIZE);
+    }
+
+    #[test]
+    fn value_outlives_arena_handle() {
+        let arena = Arena::new();
+        let value = arena.alloc(String::from("node"));
+        let weak: WeakDom<String> = Dom::downgrade(&value);
+
+        drop(arena);
+
+        assert_eq!(*value.borrow(), "node");
+        drop(value);
+        assert!(weak.upgrade().is_none());
+    }
+}
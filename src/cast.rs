@@ -1,11 +1,15 @@
+use std::any::{Any, TypeId};
 use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::marker::PhantomData;
 use std::num::NonZeroU32;
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
 use std::fmt;
 
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
 
-use crate::Dom;
+use crate::{Arena, Dom, DomRef, DomRefMut};
+use crate::{MaybeSync, Trace};
 
 // There has to be support for casting between the inherited interfaces.
 //
@@ -35,14 +39,21 @@ use crate::Dom;
 // Downcasting is bit more tricky. In the example above, when we get a `Node`
 // from the tree, how do we know what subtype it originated from?
 // Is it a `HTMLParagraphElement` or a `HTMLAnchorElement`?
-// This can be solved by requiring the base (e.g. `Node` in the chain above)
-// to have an ID as its first field (which consequently will also make it
-// the first field of any of its subtypes). The ID represents the topmost
-// subtype of the chain. We then map each interface ID to the ID of the
-// interface's supertype using a HashMap, which means that we can extract
-// the whole intheritance chain from this single topmost interface ID.
-
-#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+// This is solved by keeping the ID of the topmost subtype of the chain in the
+// header of the `Dom` that the value lives in. We then map each interface ID
+// to the ID of the interface's supertype using a HashMap, which means that we
+// can extract the whole intheritance chain from this single topmost interface
+// ID.
+//
+// NOTE The ID isn't stored in the value itself, since a `&mut` to any of the
+//      interfaces that the value inherits from can be handed out, e.g. by
+//      `Upcast` or by `DerefMut` to the inherited interface, and the part of
+//      the value that it points to could then be swapped with the same part
+//      of another value. Only the header knows what the whole value is, which
+//      is why references to interfaces can be upcast, but only handles and
+//      the guards of their borrows can be cast with `Cast`.
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
 pub struct InterfaceID(NonZeroU32);
 
 impl InterfaceID {
@@ -52,8 +63,33 @@ impl InterfaceID {
         InterfaceID(nonzero)
     }
 
-    pub unsafe fn zero() -> InterfaceID {
-        InterfaceID(NonZeroU32::new_unchecked(0))
+    // The ID of the interface with the fully qualified name, e.g.
+    // `dom::interface::Element`, which is what `#[derive(Interface)]` uses.
+    // Two crates can't pick the same ID by accident this way, but the names
+    // could still hash to the same ID, which `Hierarchy::register` reports.
+    pub const fn from_name(name: &str) -> InterfaceID {
+        // The 32-bit FNV-1a hash.
+        let bytes = name.as_bytes();
+        let mut hash: u32 = 0x811c9dc5;
+        let mut i = 0;
+
+        while i < bytes.len() {
+            hash ^= bytes[i] as u32;
+            hash = hash.wrapping_mul(0x01000193);
+            i += 1;
+        }
+
+        // NOTE 0 isn't a valid ID, and is the least likely hash to collide.
+        match NonZeroU32::new(hash) {
+            Some(nonzero) => InterfaceID(nonzero),
+            None => InterfaceID(NonZeroU32::MIN),
+        }
+    }
+}
+
+impl InterfaceID {
+    pub(crate) fn get(self) -> u32 {
+        self.0.get()
     }
 }
 
@@ -64,117 +100,915 @@ impl fmt::Display for InterfaceID {
 }
 
 // A convenience function to calculate if the interface whose ID is `top`
-// has a supertype whose ID is `sought`.
-// TODO This can probably be inserted into Interface::is()
+// has a supertype whose ID is `sought`, according to the global hierarchy.
 fn is(top: InterfaceID, sought: InterfaceID) -> bool {
-    let mut curr = Some(top);
-
-    while let Some(id) = curr {
-        if id == sought {
-            break;
-        }
+    match Registry::global() {
+        Some(registry) => registry.is(top, sought),
+        None => HIERARCHY.read().unwrap().is(top, sought),
+    }
+}
 
-        curr = HIERARCHY.read().unwrap().inherits_from(id);
+// The table of methods of an interface in the global hierarchy.
+pub(crate) fn methods_of<M: Any>(id: InterfaceID) -> Option<&'static M> {
+    match Registry::global() {
+        Some(registry) => registry.methods(id),
+        None => HIERARCHY.read().ok()?.methods(id),
     }
+}
 
-    curr.is_some()
+// The name of an interface in the global hierarchy, e.g. for messages.
+fn name_of(id: InterfaceID) -> Option<&'static str> {
+    match Registry::global() {
+        Some(registry) => registry.name(id),
+        None => HIERARCHY.read().ok()?.name(id),
+    }
 }
 
-// This trait MUST be implemented for each DOM interface.
-pub trait Interface {
+// This trait MUST be implemented for each DOM interface, preferably with
+// `#[derive(Interface)]`, see `interface/mod.rs`.
+pub trait Interface: 'static {
     // Simply gives the ID of the interface it is implemented for.
     fn id() -> InterfaceID;
 
-    fn is<U: Interface>(&self) -> bool {
-        // The base interface, which will be the innermost struct,
-        // MUST have the ID of the topmost interface as its first field.
-        let top_id = unsafe { *(self as *const Self as *const u32) };
-        let top_id = InterfaceID::new(top_id);
-        let sought_id = U::id();
-        is(top_id, sought_id)
+    // The ID of the interface that this one inherits from directly, if any.
+    fn base() -> Option<InterfaceID> where Self: Sized {
+        None
+    }
+
+    // The name that the interface is registered with, e.g. in messages.
+    fn name() -> &'static str where Self: Sized {
+        std::any::type_name::<Self>()
+    }
+
+    // Registers the interface, along with its tables of methods, if any, see
+    // `Hierarchy::register_interface` and `Hierarchy::register_methods`.
+    fn register(hier: &mut Hierarchy) -> Result<(), HierarchyError> where Self: Sized {
+        hier.register_interface::<Self>()
+    }
+
+    // Run on the `Dom` that a value of the interface is created in, see
+    // `into_dom`, e.g. so that a node can hand out handles to itself.
+    fn created(_this: &Dom<Self>) where Self: Sized {}
+
+    // Creates a `Dom` for the value, as its top-most interface.
+    fn into_dom(self) -> Dom<Self> where Self: Sized + Trace + MaybeSync + 'static {
+        let dom = Dom::new_traced(self);
+        Dom::top_interface(&dom);
+        Self::created(&dom);
+        dom
+    }
+
+    // Like `into_dom`, but the allocation lives in the arena.
+    fn into_dom_in(self, arena: &Arena) -> Dom<Self> where Self: Sized + Trace + MaybeSync + 'static {
+        let dom = arena.alloc_traced(self);
+        Dom::top_interface(&dom);
+        Self::created(&dom);
+        dom
+    }
+}
+
+// NOTE The checks are done on the handle rather than on the value, since only
+//      the header knows the top-most interface of the value, see above.
+impl<T: Interface> Dom<T> {
+    // Tells if the value is a `U`, i.e. if its top-most interface is `U` or
+    // inherits from it, according to the global hierarchy.
+    pub fn is<U: Interface>(&self) -> bool {
+        is(Dom::top_interface(self), U::id())
+    }
+
+    // Like `is`, according to the registry rather than the global hierarchy,
+    // e.g. the one of a document, see `Document::registry`.
+    pub fn is_in<U: Interface>(&self, registry: &Registry) -> bool {
+        registry.is(Dom::top_interface(self), U::id())
     }
 }
 
 // Keeps track of the interface hierarchy by mapping an interface ID to the ID
-// of the interface it inherits from.
+// of the interface it inherits from, along with the name of the interface.
 pub struct Hierarchy {
-    map: HashMap<InterfaceID, Option<InterfaceID>>,
+    map: HashMap<InterfaceID, Entry>,
+    // Set once the hierarchy has been frozen into the global registry.
+    frozen: bool,
+}
+
+struct Entry {
+    inherited: Option<InterfaceID>,
+    name: &'static str,
+    methods: Vec<Methods>,
+}
+
+// A table of methods that the interface overrides, see `register_methods`.
+type Methods = (TypeId, &'static (dyn Any + Send + Sync));
+
+impl Default for Hierarchy {
+    fn default() -> Self {
+        Hierarchy::new()
+    }
 }
 
 impl Hierarchy {
+    // An empty hierarchy, e.g. to build a `Registry` of its own.
+    pub fn new() -> Self {
+        Hierarchy {
+            map: HashMap::new(),
+            frozen: false,
+        }
+    }
+
     fn inherits_from(&self, id: InterfaceID) -> Option<InterfaceID> {
-        *self.map.get(&id)
+        self.map.get(&id)
             .unwrap_or_else(|| panic!("No interface with ID {} has been registered to the interface hierarchy", id))
+            .inherited
+    }
+
+    // Walks the hierarchy, see `Registry::is` for a faster check.
+    fn is(&self, top: InterfaceID, sought: InterfaceID) -> bool {
+        let mut curr = Some(top);
+
+        while let Some(id) = curr {
+            if id == sought {
+                break;
+            }
+
+            curr = self.inherits_from(id);
+        }
+
+        curr.is_some()
+    }
+
+    // Fails if an interface with the same ID has already been registered,
+    // since a mapping should never have to be updated. Either the interface
+    // was registered twice, or two interfaces have the same ID.
+    //
+    // The interface that is inherited from MUST be registered first, which
+    // also keeps an interface from inheriting from itself.
+    pub fn register(&mut self, interface: InterfaceID, inherited: Option<InterfaceID>, name: &'static str) -> Result<(), HierarchyError> {
+        if self.frozen {
+            return Err(HierarchyError::Frozen);
+        }
+
+        if let Some(entry) = self.map.get(&interface) {
+            return Err(HierarchyError::Duplicate {
+                id: interface,
+                registered: entry.name,
+                name,
+            });
+        }
+
+        if let Some(base) = inherited {
+            if !self.map.contains_key(&base) {
+                return Err(HierarchyError::UnknownBase {
+                    id: interface,
+                    name,
+                    base,
+                });
+            }
+        }
+
+        self.map.insert(interface, Entry {
+            inherited,
+            name,
+            methods: Vec::new(),
+        });
+
+        Ok(())
+    }
+
+    // Registers the interface along with the one it inherits from directly,
+    // see `Interface::base`.
+    pub fn register_interface<T: Interface>(&mut self) -> Result<(), HierarchyError> {
+        self.register(T::id(), T::base(), T::name())
+    }
+
+    // Registers a table of methods for the interface, which overrides the
+    // table of the same type of the interfaces it inherits from. This is how
+    // the behaviour that differs by interface is dispatched on the top-most
+    // interface, see `Registry::methods` and `NodeMethods`.
+    //
+    // The interface MUST be registered first, and can only have one table of
+    // each type.
+    pub fn register_methods<M: Any + Send + Sync>(&mut self, interface: InterfaceID, methods: &'static M) -> Result<(), HierarchyError> {
+        if self.frozen {
+            return Err(HierarchyError::Frozen);
+        }
+
+        let entry = self.map.get_mut(&interface)
+            .ok_or(HierarchyError::UnknownInterface {
+                id: interface,
+            })?;
+
+        if entry.methods.iter().any(|&(type_id, _)| type_id == TypeId::of::<M>()) {
+            return Err(HierarchyError::DuplicateMethods {
+                id: interface,
+                name: entry.name,
+                methods: std::any::type_name::<M>(),
+            });
+        }
+
+        entry.methods.push((TypeId::of::<M>(), methods));
+
+        Ok(())
+    }
+
+    // The table of methods of the interface, or of the nearest interface it
+    // inherits from that has one.
+    pub fn methods<M: Any>(&self, id: InterfaceID) -> Option<&'static M> {
+        let mut curr = Some(id);
+
+        while let Some(id) = curr {
+            let entry = self.map.get(&id)?;
+
+            if let Some(&(_, methods)) = entry.methods.iter().find(|&&(type_id, _)| type_id == TypeId::of::<M>()) {
+                return (methods as &dyn Any).downcast_ref();
+            }
+
+            curr = entry.inherited;
+        }
+
+        None
+    }
+
+    // Checks that every interface inherits from an interface that has been
+    // registered, and that no interface ends up inheriting from itself.
+    // Registering through `register` can't break this, but it is checked
+    // once more before the hierarchy is frozen, rather than during a cast.
+    pub fn validate(&self) -> Result<(), HierarchyError> {
+        for (&id, entry) in &self.map {
+            let mut curr = entry.inherited;
+            let mut steps = 0;
+
+            while let Some(base) = curr {
+                // NOTE A chain can't be longer than the number of interfaces,
+                //      unless an interface ends up inheriting from itself.
+                if base == id || steps == self.map.len() {
+                    return Err(HierarchyError::Cycle {
+                        id,
+                        name: entry.name,
+                    });
+                }
+
+                curr = self.map.get(&base)
+                    .ok_or(HierarchyError::UnknownBase {
+                        id,
+                        name: entry.name,
+                        base,
+                    })?
+                    .inherited;
+
+                steps += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Validates the interfaces that have been registered so far, and copies
+    // them into a registry. More interfaces can still be registered, and
+    // built into another registry.
+    pub fn build(&self) -> Result<Registry, HierarchyError> {
+        self.validate()?;
+        Ok(Registry::new(self))
+    }
+
+    // The name that the interface was registered with.
+    pub fn name(&self, id: InterfaceID) -> Option<&'static str> {
+        self.map.get(&id).map(|entry| entry.name)
+    }
+
+    // The interfaces that the interface inherits from, starting with the one
+    // it inherits from directly. Empty if the interface isn't registered.
+    pub fn ancestors(&self, id: InterfaceID) -> Vec<InterfaceID> {
+        let mut ancestors = Vec::new();
+        let mut curr = self.map.get(&id).and_then(|entry| entry.inherited);
+
+        while let Some(id) = curr {
+            ancestors.push(id);
+            curr = self.map.get(&id).and_then(|entry| entry.inherited);
+        }
+
+        ancestors
+    }
+
+    // The interfaces that inherit from the interface, directly or not,
+    // ordered by depth and then by name.
+    pub fn descendants(&self, id: InterfaceID) -> Vec<InterfaceID> {
+        let mut descendants: Vec<_> = self.map.keys()
+            .copied()
+            .filter(|&other| other != id && self.ancestors(other).contains(&id))
+            .collect();
+
+        descendants.sort_by_key(|&id| (self.depth(id), self.map[&id].name));
+        descendants
+    }
+
+    // The number of interfaces that the interface inherits from, which is 0
+    // for a base interface.
+    pub fn depth(&self, id: InterfaceID) -> Option<usize> {
+        self.map.contains_key(&id).then(|| self.ancestors(id).len())
+    }
+
+    // The inheritance graph in the Graphviz DOT format, with an edge from
+    // each interface to the one it inherits from.
+    // NOTE The nodes are keyed by ID, since names don't have to be unique.
+    pub fn to_dot(&self) -> String {
+        let mut entries: Vec<_> = self.map.iter().collect();
+        entries.sort_by_key(|(&id, entry)| (entry.name, id.0));
+
+        let mut dot = String::from("digraph interfaces {\n");
+
+        for (id, entry) in &entries {
+            dot.push_str(&format!("    i{} [label={:?}];\n", id, entry.name));
+        }
+
+        for (id, entry) in &entries {
+            if let Some(base) = entry.inherited {
+                dot.push_str(&format!("    i{} -> i{};\n", id, base));
+            }
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+// Returned when an interface can't be registered, or when the hierarchy turns
+// out to be invalid, see `Hierarchy::validate`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HierarchyError {
+    // The hierarchy has been frozen by `init`.
+    Frozen,
+    // An interface with the ID has already been registered.
+    Duplicate {
+        id: InterfaceID,
+        registered: &'static str,
+        name: &'static str,
+    },
+    // The interface inherits from one that hasn't been registered.
+    UnknownBase {
+        id: InterfaceID,
+        name: &'static str,
+        base: InterfaceID,
+    },
+    // The interface inherits from itself, directly or not.
+    Cycle {
+        id: InterfaceID,
+        name: &'static str,
+    },
+    // The interface hasn't been registered, e.g. before its methods are.
+    UnknownInterface {
+        id: InterfaceID,
+    },
+    // The interface already has a table of methods of the type.
+    DuplicateMethods {
+        id: InterfaceID,
+        name: &'static str,
+        methods: &'static str,
+    },
+}
+
+impl fmt::Display for HierarchyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            HierarchyError::Frozen => {
+                write!(f, "The interface hierarchy has been frozen, so interfaces can't be registered after `init`")
+            },
+            HierarchyError::Duplicate { id, registered, name } if registered == name => {
+                write!(f, "The interface {} (ID {}) has already been registered", name, id)
+            },
+            HierarchyError::Duplicate { id, registered, name } => {
+                write!(f, "The interface ID {} of {} is already used by {}", id, name, registered)
+            },
+            HierarchyError::UnknownBase { id, name, base } => {
+                write!(f, "The interface {} (ID {}) inherits from the interface with ID {}, which hasn't been registered", name, id, base)
+            },
+            HierarchyError::Cycle { id, name } => {
+                write!(f, "The interface {} (ID {}) inherits from itself", name, id)
+            },
+            HierarchyError::UnknownInterface { id } => {
+                write!(f, "No interface with ID {} has been registered to the interface hierarchy", id)
+            },
+            HierarchyError::DuplicateMethods { id, name, methods } => {
+                write!(f, "The interface {} (ID {}) already has methods of type {}", name, id, methods)
+            },
+        }
+    }
+}
+
+impl std::error::Error for HierarchyError {}
+
+// The global hierarchy, which the interfaces are registered to before `init`
+// freezes it into the global `Registry`.
+pub static HIERARCHY: Lazy<RwLock<Hierarchy>> = Lazy::new(|| RwLock::new(Hierarchy::new()));
+
+// Every `is` check, e.g. when filtering the nodes of a tree by interface,
+// would otherwise take the lock once per step up the hierarchy. Since the
+// hierarchy doesn't change once all interfaces have been registered, it is
+// frozen into a registry that can be read without any locks.
+//
+// Each interface is mapped to its ancestors, ordered by depth, from the base
+// interface to the interface itself. An interface `top` is then a `sought`
+// if the ancestor of `top` at the depth of `sought` is `sought`, which takes
+// two lookups no matter how deep the hierarchy is.
+//
+// The global registry is frozen from `HIERARCHY` by `init`, and is used by
+// `Dom::is` and `Cast`. Other registries are built from a `Hierarchy`
+// of their own, e.g. so that tests or embedders that register different
+// interfaces don't share them, and are used by `Dom::is_in` and
+// `Cast::try_cast_in`. A document can be created against one as well, see
+// `Document::create_with_registry`, in which case the methods of its nodes
+// are dispatched according to it, see `Node::registry`.
+#[derive(Clone)]
+pub struct Registry {
+    // NOTE Always an `Arc`, since the global registry is shared by threads.
+    inner: Arc<Frozen>,
+}
+
+static GLOBAL: OnceCell<Registry> = OnceCell::new();
+
+struct Frozen {
+    ancestors: HashMap<InterfaceID, Box<[InterfaceID]>, BuildHasherDefault<IdHasher>>,
+    names: HashMap<InterfaceID, &'static str, BuildHasherDefault<IdHasher>>,
+    // The tables of methods of each interface, including the ones that are
+    // inherited, so that they are found without walking the hierarchy.
+    methods: HashMap<InterfaceID, Box<[Methods]>, BuildHasherDefault<IdHasher>>,
+}
+
+impl Registry {
+    fn new(hierarchy: &Hierarchy) -> Registry {
+        let mut ancestors = HashMap::default();
+        let mut names = HashMap::default();
+        let mut methods = HashMap::default();
+
+        for (&id, entry) in &hierarchy.map {
+            names.insert(id, entry.name);
+
+            let mut chain = vec![id];
+
+            // NOTE The hierarchy has been validated, so the chain ends.
+            while let Some(base) = hierarchy.inherits_from(*chain.last().unwrap()) {
+                chain.push(base);
+            }
+
+            // The tables of the interfaces further down the chain come first,
+            // so only the ones that aren't overridden are added.
+            let mut tables: Vec<Methods> = Vec::new();
+
+            for base in &chain {
+                for &(type_id, table) in &hierarchy.map[base].methods {
+                    if tables.iter().all(|&(other, _)| other != type_id) {
+                        tables.push((type_id, table));
+                    }
+                }
+            }
+
+            methods.insert(id, tables.into_boxed_slice());
+
+            chain.reverse();
+            ancestors.insert(id, chain.into_boxed_slice());
+        }
+
+        Registry {
+            inner: Arc::new(Frozen {
+                ancestors,
+                names,
+                methods,
+            }),
+        }
+    }
+
+    // The global registry, once `init` has frozen it.
+    pub fn global() -> Option<&'static Registry> {
+        GLOBAL.get()
     }
 
-    // NOTE Hashmap::insert() returns the old value if the key was already
-    //      present, but it isn't checked or handled. Should it be? I don't
-    //      think that a mapping should ever have to be updated, so it might
-    //      be a good idea to check if that happens, because it is probably
-    //      an error.
-    pub fn register(&mut self, interface: InterfaceID, inherited: Option<InterfaceID>) {
-        let res = self.map.insert(interface, inherited);
-        debug_assert!(res.is_none());
+    pub fn contains(&self, id: InterfaceID) -> bool {
+        self.inner.ancestors.contains_key(&id)
     }
 
-    // The test could will do repeated initialization, so we have to introduce
-    // the ability to clear the map when testing.
-    #[cfg(test)]
-    pub(crate) fn clear(&mut self) {
-        self.map.clear();
+    // The name that the interface was registered with.
+    pub fn name(&self, id: InterfaceID) -> Option<&'static str> {
+        self.inner.names.get(&id).copied()
     }
+
+    // The number of interfaces that the interface inherits from.
+    pub fn depth(&self, id: InterfaceID) -> Option<usize> {
+        self.inner.ancestors.get(&id).map(|ancestors| ancestors.len() - 1)
+    }
+
+    // The table of methods of the interface, or the one it inherits, see
+    // `Hierarchy::register_methods`.
+    pub fn methods<M: Any>(&self, id: InterfaceID) -> Option<&'static M> {
+        self.inner.methods.get(&id)?
+            .iter()
+            .find(|&&(type_id, _)| type_id == TypeId::of::<M>())
+            .and_then(|&(_, methods)| (methods as &dyn Any).downcast_ref())
+    }
+
+    // Tells if the interface whose ID is `top` is, or inherits from, the
+    // interface whose ID is `sought`.
+    pub fn is(&self, top: InterfaceID, sought: InterfaceID) -> bool {
+        let ancestors = self.inner.ancestors.get(&top)
+            .unwrap_or_else(|| panic!("No interface with ID {} has been registered to the interface hierarchy", top));
+
+        match self.inner.ancestors.get(&sought) {
+            Some(sought_ancestors) => ancestors.get(sought_ancestors.len() - 1) == Some(&sought),
+            None => false,
+        }
+    }
+
+    // Tells if the handles are to the same registry.
+    pub fn ptr_eq(&self, other: &Registry) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+// Freezes the interfaces that have been registered so far, after which no
+// more interfaces can be registered. Called by `init`.
+pub(crate) fn freeze() -> Result<(), HierarchyError> {
+    let mut hierarchy = HIERARCHY.write().unwrap();
+    let registry = hierarchy.build()?;
+
+    hierarchy.frozen = true;
+    GLOBAL.get_or_init(|| registry);
+
+    Ok(())
 }
 
-pub static HIERARCHY: Lazy<RwLock<Hierarchy>> = Lazy::new(|| {
-    RwLock::new(Hierarchy {
-        map: HashMap::new(),
-    })
-});
+// The IDs are either picked by hand or hashes of names already, so they are
+// only spread over the bits of the hash.
+#[derive(Default)]
+struct IdHasher(u64);
+
+impl Hasher for IdHasher {
+    fn finish(&self) -> u64 {
+        self.0.wrapping_mul(0x9e3779b97f4a7c15)
+    }
 
-// This trait is implemented on types that contains interfaces, e.g. &T and
-// Dom<T>, so that we can cast the contained interface.
-// TODO The `cast` function should probably return an `Option` so it doesn't
-//      have to panic if the cast wasn't possible to perform.
-//      The `cast` could also be split into `upcast` and `downcast`. Upcasting
-//      could then be statically checked using a trait that indicates that an
-//      interface inherits from another interface.
-pub trait Cast<T: Interface, U: Interface> {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = self.0 << 8 | byte as u64;
+        }
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        self.0 = value as u64;
+    }
+}
+
+// This trait is implemented on types that contains interfaces, e.g. Dom<T>
+// and the guards of its borrows, so that we can cast the contained interface.
+// NOTE Prefer `Upcast` when casting to an interface that is inherited from,
+//      since it doesn't have to check the hierarchy.
+pub trait Cast<T: Interface, U: Interface>: Sized {
     // The result of casting from T to U.
     type Res;
 
-    fn cast(self) -> Self::Res;
+    // Casts the value, or gives it back in the error if it isn't a `U`,
+    // according to the global hierarchy.
+    fn try_cast(self) -> Result<Self::Res, CastError<Self>>;
+
+    // Casts the value, and panics if it isn't a `U`.
+    fn cast(self) -> Self::Res {
+        self.try_cast().unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    // Like `try_cast`, according to the registry rather than the global
+    // hierarchy.
+    fn try_cast_in(self, registry: &Registry) -> Result<Self::Res, CastError<Self>>;
+
+    fn cast_in(self, registry: &Registry) -> Self::Res {
+        self.try_cast_in(registry).unwrap_or_else(|err| panic!("{}", err))
+    }
 }
 
 impl<T: Interface, U: Interface> Cast<T, U> for Dom<T> {
     type Res = Dom<U>;
 
-    fn cast(self) -> Self::Res {
-        if self.is::<U>() {
-            unsafe { std::mem::transmute::<Dom<T>, Dom<U>>(self) }
-        } else {
-            panic!();
+    fn try_cast(self) -> Result<Self::Res, CastError<Self>> {
+        let top = Dom::top_interface(&self);
+        cast_with::<T, U, _, _>(self, top, is(top, U::id()), name_of, |dom| unsafe { dom.cast_unchecked() })
+    }
+
+    fn try_cast_in(self, registry: &Registry) -> Result<Self::Res, CastError<Self>> {
+        let top = Dom::top_interface(&self);
+        cast_with::<T, U, _, _>(self, top, registry.is(top, U::id()), |id| registry.name(id), |dom| unsafe { dom.cast_unchecked() })
+    }
+}
+
+// Casts the borrow, e.g. to mutate a node as an `Element` after it was
+// borrowed as a `Node`.
+impl<'a, T: Interface, U: 'a + Interface> Cast<T, U> for DomRef<'a, T> {
+    type Res = DomRef<'a, U>;
+
+    fn try_cast(self) -> Result<Self::Res, CastError<Self>> {
+        let top = Dom::top_interface(DomRef::dom(&self));
+        cast_with::<T, U, _, _>(self, top, is(top, U::id()), name_of, |value| unsafe { DomRef::cast_unchecked(value) })
+    }
+
+    fn try_cast_in(self, registry: &Registry) -> Result<Self::Res, CastError<Self>> {
+        let top = Dom::top_interface(DomRef::dom(&self));
+        cast_with::<T, U, _, _>(self, top, registry.is(top, U::id()), |id| registry.name(id), |value| unsafe { DomRef::cast_unchecked(value) })
+    }
+}
+
+impl<'a, T: Interface, U: 'a + Interface> Cast<T, U> for DomRefMut<'a, T> {
+    type Res = DomRefMut<'a, U>;
+
+    fn try_cast(self) -> Result<Self::Res, CastError<Self>> {
+        let top = Dom::top_interface(DomRefMut::dom(&self));
+        cast_with::<T, U, _, _>(self, top, is(top, U::id()), name_of, |value| unsafe { DomRefMut::cast_unchecked(value) })
+    }
+
+    fn try_cast_in(self, registry: &Registry) -> Result<Self::Res, CastError<Self>> {
+        let top = Dom::top_interface(DomRefMut::dom(&self));
+        cast_with::<T, U, _, _>(self, top, registry.is(top, U::id()), |id| registry.name(id), |value| unsafe { DomRefMut::cast_unchecked(value) })
+    }
+}
+
+// Casts the contained value, if any. `None` is cast to `None`, e.g. to cast
+// the result of `Node::first_child` right away.
+impl<T: Interface, U: Interface, S: Cast<T, U>> Cast<T, U> for Option<S> {
+    type Res = Option<S::Res>;
+
+    fn try_cast(self) -> Result<Self::Res, CastError<Self>> {
+        match self {
+            Some(value) => value.try_cast().map(Some).map_err(|err| err.map(Some)),
+            None => Ok(None),
+        }
+    }
+
+    fn try_cast_in(self, registry: &Registry) -> Result<Self::Res, CastError<Self>> {
+        match self {
+            Some(value) => value.try_cast_in(registry).map(Some).map_err(|err| err.map(Some)),
+            None => Ok(None),
         }
     }
 }
 
-impl<'a, T: Interface, U: 'a + Interface> Cast<T, U> for &'a T {
+// Casts the value with `cast` if it turned out to be a `U`. Otherwise the name
+// of `top`, the interface that the value actually is, is looked up with
+// `name`.
+fn cast_with<T: Interface, U: Interface, S, R>(
+    value: S,
+    top: InterfaceID,
+    is: bool,
+    name: impl FnOnce(InterfaceID) -> Option<&'static str>,
+    cast: impl FnOnce(S) -> R,
+) -> Result<R, CastError<S>> {
+    if is {
+        Ok(cast(value))
+    } else {
+        Err(CastError::new::<T, U>(value, (top, name(top))))
+    }
+}
+
+// Names the interface that a type which implements `Cast` contains, so that
+// it doesn't have to be spelled out, see `CastIterator`.
+pub trait Contains {
+    type Interface: Interface;
+}
+
+impl<T: Interface> Contains for Dom<T> {
+    type Interface = T;
+}
+
+impl<T: Interface> Contains for DomRef<'_, T> {
+    type Interface = T;
+}
+
+impl<T: Interface> Contains for DomRefMut<'_, T> {
+    type Interface = T;
+}
+
+impl<S: Contains> Contains for Option<S> {
+    type Interface = S::Interface;
+}
+
+// Iterator adapters that cast the items, e.g. to only visit the elements
+// among the children of a node:
+// ```
+// let elements = children.filter_cast::<Element>();
+// ```
+pub trait CastIterator: Iterator + Sized {
+    // Yields the items that are a `U`, cast to it, and skips the others.
+    fn filter_cast<U: Interface>(self) -> FilterCast<Self, U>
+    where
+        Self::Item: Contains + Cast<<Self::Item as Contains>::Interface, U>,
+    {
+        FilterCast {
+            iter: self,
+            registry: None,
+            _marker: PhantomData,
+        }
+    }
+
+    // Like `filter_cast`, according to the registry rather than the global
+    // hierarchy.
+    fn filter_cast_in<U: Interface>(self, registry: &Registry) -> FilterCast<Self, U>
+    where
+        Self::Item: Contains + Cast<<Self::Item as Contains>::Interface, U>,
+    {
+        FilterCast {
+            iter: self,
+            registry: Some(registry.clone()),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<I: Iterator> CastIterator for I {}
+
+// See `CastIterator::filter_cast`.
+pub struct FilterCast<I, U> {
+    iter: I,
+    registry: Option<Registry>,
+    _marker: PhantomData<fn() -> U>,
+}
+
+impl<I, U> Iterator for FilterCast<I, U>
+where
+    I: Iterator,
+    I::Item: Contains + Cast<<I::Item as Contains>::Interface, U>,
+    U: Interface,
+{
+    type Item = <I::Item as Cast<<I::Item as Contains>::Interface, U>>::Res;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for item in &mut self.iter {
+            let cast = match &self.registry {
+                Some(registry) => item.try_cast_in(registry),
+                None => item.try_cast(),
+            };
+
+            if let Ok(cast) = cast {
+                return Some(cast);
+            }
+        }
+
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.iter.size_hint().1)
+    }
+}
+
+// Marks the interfaces that an interface inherits from, so that it can be
+// upcast to them without looking at the hierarchy, see `Upcast`.
+//
+/// # Safety
+///
+/// `Base` MUST be the interface itself, or one of the interfaces that it
+/// inherits from, directly or through other interfaces. In other words, the
+/// struct of the interface MUST start with a `Base`, see `interface/mod.rs`.
+pub unsafe trait Inherits<Base: Interface>: Interface {}
+
+// SAFETY Every interface starts with itself.
+unsafe impl<T: Interface> Inherits<T> for T {}
+
+// Casts the contained interface to one of the interfaces it inherits from.
+// Unlike `Cast`, this is checked by the type system and can't fail, so it
+// doesn't do any work at runtime.
+pub trait Upcast<T: Interface, U: Interface> {
+    // The result of casting from T to U.
+    type Res;
+
+    fn upcast(self) -> Self::Res;
+}
+
+impl<T: Inherits<U>, U: Interface> Upcast<T, U> for Dom<T> {
+    type Res = Dom<U>;
+
+    fn upcast(self) -> Self::Res {
+        unsafe { self.cast_unchecked() }
+    }
+}
+
+impl<'a, T: Inherits<U>, U: 'a + Interface> Upcast<T, U> for &'a T {
     type Res = &'a U;
 
-    fn cast(self) -> Self::Res {
-        if self.is::<U>() {
-            unsafe { &*(self as *const T as *const U) }
-        } else {
-            panic!();
+    fn upcast(self) -> Self::Res {
+        unsafe { &*(self as *const T as *const U) }
+    }
+}
+
+impl<'a, T: Inherits<U>, U: 'a + Interface> Upcast<T, U> for &'a mut T {
+    type Res = &'a mut U;
+
+    fn upcast(self) -> Self::Res {
+        unsafe { &mut *(self as *mut T as *mut U) }
+    }
+}
+
+// Returned by `Cast::try_cast` when the value isn't of the interface it was
+// cast to, along with the value that was cast so that it isn't lost.
+pub struct CastError<S> {
+    value: S,
+    from: (InterfaceID, &'static str),
+    to: (InterfaceID, &'static str),
+    // The name is only known if the interface is registered.
+    actual: (InterfaceID, Option<&'static str>),
+}
+
+impl<S> CastError<S> {
+    fn new<T: Interface, U: Interface>(value: S, actual: (InterfaceID, Option<&'static str>)) -> Self {
+        CastError {
+            value,
+            from: (T::id(), T::name()),
+            to: (U::id(), U::name()),
+            actual,
+        }
+    }
+
+    fn map<R>(self, f: impl FnOnce(S) -> R) -> CastError<R> {
+        CastError {
+            value: f(self.value),
+            from: self.from,
+            to: self.to,
+            actual: self.actual,
         }
     }
+
+    // Gives back the value that was cast.
+    pub fn into_inner(self) -> S {
+        self.value
+    }
+
+    // The interface that the value was cast from.
+    pub fn from(&self) -> InterfaceID {
+        self.from.0
+    }
+
+    // The interface that the value was cast to.
+    pub fn to(&self) -> InterfaceID {
+        self.to.0
+    }
+
+    // The top-most interface of the value, i.e. the one it was created as.
+    pub fn actual(&self) -> InterfaceID {
+        self.actual.0
+    }
+
+    pub fn from_name(&self) -> &'static str {
+        self.from.1
+    }
+
+    pub fn to_name(&self) -> &'static str {
+        self.to.1
+    }
+
+    pub fn actual_name(&self) -> Option<&'static str> {
+        self.actual.1
+    }
 }
 
+// NOTE The value isn't printed, since it doesn't have to implement `Debug`.
+impl<S> fmt::Debug for CastError<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CastError")
+            .field("from", &self.from)
+            .field("to", &self.to)
+            .field("actual", &self.actual)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S> fmt::Display for CastError<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Can't cast {} (ID {}) to {} (ID {}), since the value is ", self.from.1, self.from.0, self.to.1, self.to.0)?;
+
+        match self.actual {
+            (id, Some(name)) => write!(f, "{} (ID {})", name, id),
+            (id, None) => write!(f, "an interface with ID {}", id),
+        }
+    }
+}
+
+impl<S> std::error::Error for CastError<S> {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::Interface;
+    use std::rc::Rc;
+    use std::cell::Cell;
 
+    #[allow(dead_code)]
     struct InterfaceA(InterfaceB);
-    struct InterfaceB(InterfaceID, u32);
+    #[allow(dead_code)]
+    struct InterfaceB(u32);
+    #[allow(dead_code)]
+    #[repr(C)]
+    struct InterfaceC(InterfaceB, DropFlag);
+
+    // Sets the flag when it is dropped.
+    struct DropFlag(Rc<Cell<bool>>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.set(true);
+        }
+    }
 
     impl Interface for InterfaceA {
         fn id() -> InterfaceID {
@@ -188,41 +1022,410 @@ mod tests {
         }
     }
 
-    // Each test function should call this initialization.
-    fn interface_init() {
-        // We have to clear the hierarchy first, since this function will be
-        // called multiple times in the same binary, which would result in a
-        // panic because we are trying to insert an InterfaceID that already
-        // exist.
-        let mut hier = HIERARCHY.write().unwrap();
-        hier.clear();
-        hier.register(InterfaceA::id(), Some(InterfaceB::id()));
-        hier.register(InterfaceB::id(), None);
+    impl Interface for InterfaceC {
+        fn id() -> InterfaceID {
+            InterfaceID::new(15)
+        }
+    }
+
+    unsafe impl Inherits<InterfaceB> for InterfaceA {}
+    unsafe impl Inherits<InterfaceB> for InterfaceC {}
+
+    // A chain of derived interfaces, where `Derived` is the base.
+    #[derive(Interface)]
+    #[interface(crate = crate)]
+    #[repr(C)]
+    struct Derived {
+        value: u32,
+    }
+
+    #[derive(Interface)]
+    #[interface(crate = crate, extends(Derived))]
+    #[repr(C)]
+    struct DerivedChild(Derived);
+
+    #[derive(Interface)]
+    #[interface(crate = crate, extends(DerivedChild))]
+    #[repr(C)]
+    struct DerivedGrandchild {
+        _inherited: DerivedChild,
+        #[allow(dead_code)]
+        name: String,
+    }
+
+    // The interfaces of the tests are kept out of the global hierarchy, in a
+    // registry of their own.
+    static HIER: Lazy<Hierarchy> = Lazy::new(|| {
+        let mut hier = Hierarchy::new();
+        hier.register(InterfaceB::id(), None, "InterfaceB").unwrap();
+        hier.register(InterfaceA::id(), Some(InterfaceB::id()), "InterfaceA").unwrap();
+        hier.register(InterfaceC::id(), Some(InterfaceB::id()), "InterfaceC").unwrap();
+        hier.register_interface::<Derived>().unwrap();
+        hier.register_interface::<DerivedChild>().unwrap();
+        hier.register_interface::<DerivedGrandchild>().unwrap();
+        hier
+    });
+
+    static REGISTRY: Lazy<Registry> = Lazy::new(|| HIER.build().unwrap());
+
+    // Creates the value as its top-most interface, like `Interface::into_dom`
+    // does for the interfaces that are traced.
+    fn create<T: Interface>(value: T) -> Dom<T> {
+        let dom = Dom::new(value);
+        Dom::top_interface(&dom);
+        dom
     }
 
     #[test]
     fn simple_cast() {
-        interface_init();
-        let a = Dom::new(InterfaceA(InterfaceB(InterfaceA::id(), 35)));
-        let b: Dom<InterfaceB> = Dom::clone(&a).cast();
+        let a = create(InterfaceA(InterfaceB(35)));
+        let b: Dom<InterfaceB> = Dom::clone(&a).cast_in(&REGISTRY);
 
-        assert!(b.1 == 35);
+        assert!(b.borrow().0 == 35);
     }
 
     #[test]
     fn simple_is_upcast() {
-        interface_init(); 
-        let a = Dom::new(InterfaceA(InterfaceB(InterfaceA::id(), 35)));
+        let a = create(InterfaceA(InterfaceB(35)));
 
-        assert!(a.is::<InterfaceB>());
+        assert!(a.is_in::<InterfaceB>(&REGISTRY));
     }
 
     #[test]
     fn simple_is_downcast() {
-        interface_init(); 
-        let a = Dom::new(InterfaceA(InterfaceB(InterfaceA::id(), 35)));
-        let b: Dom<InterfaceB> = a.cast();
+        let a = create(InterfaceA(InterfaceB(35)));
+        let b: Dom<InterfaceB> = a.cast_in(&REGISTRY);
+
+        assert!(b.is_in::<InterfaceA>(&REGISTRY));
+    }
+
+    #[test]
+    fn drop_after_upcast() {
+        let dropped = Rc::new(Cell::new(false));
+        let c = create(InterfaceC(InterfaceB(35), DropFlag(Rc::clone(&dropped))));
+        let b: Dom<InterfaceB> = c.cast_in(&REGISTRY);
+
+        assert!(b.borrow().0 == 35);
+
+        // The last handle is a Dom<InterfaceB>, but the whole InterfaceC has
+        // to be dropped.
+        drop(b);
+        assert!(dropped.get());
+    }
+
+    #[test]
+    fn failed_cast_gives_back_value() {
+        let a = create(InterfaceA(InterfaceB(35)));
+        let b: Dom<InterfaceB> = Dom::clone(&a).cast_in(&REGISTRY);
+
+        let err = Cast::<InterfaceB, InterfaceC>::try_cast_in(b, &REGISTRY).unwrap_err();
+        assert_eq!(err.from(), InterfaceB::id());
+        assert_eq!(err.to(), InterfaceC::id());
+        assert_eq!(err.actual(), InterfaceA::id());
+        assert!(err.to_name().ends_with("InterfaceC"));
+        assert_eq!(err.actual_name(), Some("InterfaceA"));
+
+        let b = err.into_inner();
+        assert!(b.borrow().0 == 35);
+        assert_eq!(Dom::strong_count(&a), 2);
+    }
+
+    #[test]
+    fn failed_borrow_cast() {
+        let a = create(InterfaceA(InterfaceB(35)));
+        let b: Dom<InterfaceB> = a.upcast();
+
+        assert!(Cast::<InterfaceB, InterfaceA>::try_cast_in(b.borrow(), &REGISTRY).is_ok());
 
-        assert!(b.is::<InterfaceA>());
+        let err = Cast::<InterfaceB, InterfaceC>::try_cast_in(b.borrow(), &REGISTRY).err().unwrap();
+        assert!(err.into_inner().0 == 35);
+    }
+
+    #[test]
+    fn mut_cast() {
+        let a = create(InterfaceA(InterfaceB(35)));
+        let b: Dom<InterfaceB> = a.upcast();
+
+        let mut a: DomRefMut<'_, InterfaceA> = b.borrow_mut().cast_in(&REGISTRY);
+        (a.0).0 = 36;
+
+        // The borrow is handed over to the cast guard.
+        assert!(b.try_borrow().is_err());
+        drop(a);
+
+        let err = Cast::<InterfaceB, InterfaceC>::try_cast_in(b.borrow_mut(), &REGISTRY).err().unwrap();
+        assert!(err.into_inner().0 == 36);
+        assert!(b.try_borrow_mut().is_ok());
+    }
+
+    #[test]
+    fn option_cast() {
+        let a = create(InterfaceA(InterfaceB(35)));
+        let b: Option<Dom<InterfaceB>> = Some(Dom::clone(&a).upcast());
+
+        let cast: Option<Dom<InterfaceA>> = b.clone().cast_in(&REGISTRY);
+        assert!(cast.unwrap() == a);
+
+        let err = Cast::<InterfaceB, InterfaceC>::try_cast_in(b.as_ref().map(|b| b.borrow()), &REGISTRY).err().unwrap();
+        assert!(err.into_inner().unwrap().0 == 35);
+
+        let none: Option<DomRef<'_, InterfaceB>> = None;
+        assert!(Cast::<InterfaceB, InterfaceC>::try_cast_in(none, &REGISTRY).unwrap().is_none());
+    }
+
+    #[test]
+    fn swapped_values_keep_their_interface() {
+        let a: Dom<InterfaceB> = create(InterfaceA(InterfaceB(1))).upcast();
+        let b = create(InterfaceB(2));
+
+        // Only the `InterfaceB` parts are swapped, not what the values are.
+        std::mem::swap(&mut *a.borrow_mut(), &mut *b.borrow_mut());
+
+        assert!(a.is_in::<InterfaceA>(&REGISTRY));
+        assert!(!b.is_in::<InterfaceA>(&REGISTRY));
+        assert!(Cast::<InterfaceB, InterfaceA>::try_cast_in(b, &REGISTRY).is_err());
+        assert!(a.borrow().0 == 2);
+    }
+
+    #[test]
+    fn filter_cast() {
+        let a = create(InterfaceA(InterfaceB(1)));
+        let c = create(InterfaceC(InterfaceB(2), DropFlag(Rc::default())));
+        let nodes: Vec<Dom<InterfaceB>> = vec![Dom::clone(&a).upcast(), c.upcast(), a.upcast()];
+
+        let found: Vec<_> = nodes.iter().map(|b| b.borrow()).filter_cast_in::<InterfaceA>(&REGISTRY).collect();
+        assert_eq!(found.len(), 2);
+        assert!(found.iter().all(|a| (a.0).0 == 1));
+        drop(found);
+
+        let found: Vec<Dom<InterfaceC>> = nodes.into_iter().filter_cast_in::<InterfaceC>(&REGISTRY).collect();
+        assert_eq!(found.len(), 1);
+        assert!((found[0].borrow().0).0 == 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "since the value is InterfaceA (ID 13)")]
+    fn failed_cast_panics() {
+        let a = create(InterfaceA(InterfaceB(35)));
+        let _: Dom<InterfaceC> = Cast::<InterfaceA, InterfaceC>::cast_in(a, &REGISTRY);
+    }
+
+    #[test]
+    fn upcast_without_hierarchy() {
+        // NOTE The hierarchy isn't initialized, since upcasts don't need it.
+        let mut a = InterfaceA(InterfaceB(35));
+
+        let b: &mut InterfaceB = (&mut a).upcast();
+        b.0 = 36;
+
+        let b: &InterfaceB = (&a).upcast();
+        assert!(b.0 == 36);
+
+        let a = create(a);
+        let b: Dom<InterfaceB> = a.upcast();
+        assert!(b.borrow().0 == 36);
+    }
+
+    #[test]
+    #[should_panic(expected = "upcast before it was checked")]
+    fn upcast_before_interface_is_known() {
+        let b: Dom<InterfaceB> = Dom::new(InterfaceA(InterfaceB(35))).upcast();
+        b.is_in::<InterfaceA>(&REGISTRY);
+    }
+
+    #[test]
+    fn derived_interfaces() {
+        assert_eq!(DerivedGrandchild::base(), Some(DerivedChild::id()));
+        assert_eq!(Derived::base(), None);
+
+        let grandchild = DerivedGrandchild {
+            _inherited: DerivedChild(Derived {
+                value: 35,
+            }),
+            name: String::from("grandchild"),
+        };
+
+        let grandchild = create(grandchild);
+        assert_eq!(grandchild.borrow().value, 35);
+
+        let derived: Dom<Derived> = grandchild.upcast();
+        assert!(derived.is_in::<DerivedChild>(&REGISTRY));
+
+        let child: Dom<DerivedChild> = derived.cast_in(&REGISTRY);
+        assert!(child.is_in::<DerivedGrandchild>(&REGISTRY));
+    }
+
+    #[test]
+    fn derived_id_from_name() {
+        const ID: InterfaceID = InterfaceID::from_name("dom::cast::tests::Derived");
+
+        assert_eq!(Derived::id(), ID);
+        assert_eq!(Derived::id(), InterfaceID::from_name(std::any::type_name::<Derived>()));
+        assert_ne!(DerivedChild::id(), Derived::id());
+    }
+
+    #[test]
+    fn registered_twice() {
+        let mut hier = Hierarchy::new();
+        hier.register_interface::<Derived>().unwrap();
+
+        let err = hier.register_interface::<Derived>().unwrap_err();
+        assert!(err.to_string().contains("The interface Derived (ID"));
+    }
+
+    #[test]
+    fn colliding_ids() {
+        let mut hier = Hierarchy::new();
+        hier.register(InterfaceB::id(), None, "InterfaceB").unwrap();
+        hier.register_interface::<InterfaceA>().unwrap();
+
+        let err = hier.register(InterfaceA::id(), None, "Other").unwrap_err();
+        assert_eq!(err, HierarchyError::Duplicate {
+            id: InterfaceA::id(),
+            registered: "dom::cast::tests::InterfaceA",
+            name: "Other",
+        });
+        assert!(err.to_string().contains("of Other is already used by dom::cast::tests::InterfaceA"));
+    }
+
+    #[test]
+    fn unknown_base() {
+        let mut hier = Hierarchy::new();
+
+        assert_eq!(hier.register(InterfaceA::id(), Some(InterfaceB::id()), "InterfaceA"), Err(HierarchyError::UnknownBase {
+            id: InterfaceA::id(),
+            name: "InterfaceA",
+            base: InterfaceB::id(),
+        }));
+        assert_eq!(hier.register(InterfaceA::id(), Some(InterfaceA::id()), "InterfaceA"), Err(HierarchyError::UnknownBase {
+            id: InterfaceA::id(),
+            name: "InterfaceA",
+            base: InterfaceA::id(),
+        }));
+        assert!(hier.validate().is_ok());
+    }
+
+    #[test]
+    fn registry_matches_hierarchy() {
+        let ids = [InterfaceA::id(), InterfaceB::id(), InterfaceC::id(), Derived::id(), DerivedChild::id(), DerivedGrandchild::id()];
+
+        for &top in &ids {
+            for &sought in &ids {
+                assert_eq!(REGISTRY.is(top, sought), HIER.is(top, sought));
+            }
+        }
+
+        assert!(REGISTRY.is(DerivedGrandchild::id(), Derived::id()));
+        assert!(!REGISTRY.is(Derived::id(), DerivedGrandchild::id()));
+    }
+
+    #[test]
+    fn validate_cycle() {
+        // NOTE `register` doesn't let a cycle be created.
+        let mut hier = Hierarchy::new();
+        let entry = |inherited| Entry { inherited: Some(inherited), name: "Cyclic", methods: Vec::new() };
+        hier.map.insert(InterfaceA::id(), entry(InterfaceB::id()));
+        hier.map.insert(InterfaceB::id(), entry(InterfaceC::id()));
+        hier.map.insert(InterfaceC::id(), entry(InterfaceA::id()));
+
+        let err = hier.validate().unwrap_err();
+        assert!(matches!(err, HierarchyError::Cycle { .. }));
+        assert!(err.to_string().contains("Cyclic (ID"));
+        assert!(err.to_string().contains("inherits from itself"));
+
+        hier.map.insert(InterfaceC::id(), entry(Derived::id()));
+
+        // Any of the interfaces might be found to inherit from the unknown one.
+        let err = hier.validate().unwrap_err();
+        assert!(matches!(err, HierarchyError::UnknownBase { base, .. } if base == Derived::id()));
+    }
+
+    #[test]
+    fn register_after_freeze() {
+        let mut hier = Hierarchy::new();
+        hier.frozen = true;
+        assert_eq!(hier.register_interface::<Derived>(), Err(HierarchyError::Frozen));
+    }
+
+    #[test]
+    fn registries_are_isolated() {
+        crate::tests_init::hierarchy_init();
+        let global = Registry::global().unwrap();
+
+        assert!(!global.contains(InterfaceA::id()));
+        assert!(!REGISTRY.contains(crate::interface::Node::id()));
+        assert!(!REGISTRY.ptr_eq(global));
+
+        // The hierarchy can still be built into another registry.
+        let mut hier = Hierarchy::new();
+        hier.register(InterfaceB::id(), None, "InterfaceB").unwrap();
+        let registry = hier.build().unwrap();
+
+        assert!(registry.contains(InterfaceB::id()));
+        assert!(!registry.contains(InterfaceA::id()));
+    }
+
+    #[test]
+    fn introspection() {
+        assert_eq!(HIER.name(DerivedChild::id()), Some("DerivedChild"));
+        assert_eq!(HIER.name(InterfaceID::new(1)), None);
+        assert_eq!(REGISTRY.name(InterfaceA::id()), Some("InterfaceA"));
+
+        assert_eq!(HIER.ancestors(DerivedGrandchild::id()), [DerivedChild::id(), Derived::id()]);
+        assert!(HIER.ancestors(Derived::id()).is_empty());
+        assert!(HIER.ancestors(InterfaceID::new(1)).is_empty());
+
+        assert_eq!(HIER.descendants(Derived::id()), [DerivedChild::id(), DerivedGrandchild::id()]);
+        assert_eq!(HIER.descendants(InterfaceB::id()), [InterfaceA::id(), InterfaceC::id()]);
+        assert!(HIER.descendants(DerivedGrandchild::id()).is_empty());
+
+        assert_eq!(HIER.depth(Derived::id()), Some(0));
+        assert_eq!(HIER.depth(DerivedGrandchild::id()), Some(2));
+        assert_eq!(HIER.depth(InterfaceID::new(1)), None);
+        assert_eq!(REGISTRY.depth(DerivedGrandchild::id()), Some(2));
+    }
+
+    #[test]
+    fn methods_are_inherited() {
+        struct Greeting(&'static str);
+        static HELLO: Greeting = Greeting("hello");
+        static HI: Greeting = Greeting("hi");
+
+        let mut hier = Hierarchy::new();
+        hier.register_interface::<Derived>().unwrap();
+        hier.register_interface::<DerivedChild>().unwrap();
+        hier.register_interface::<DerivedGrandchild>().unwrap();
+        hier.register_methods(Derived::id(), &HELLO).unwrap();
+        hier.register_methods(DerivedGrandchild::id(), &HI).unwrap();
+
+        assert!(matches!(hier.register_methods(Derived::id(), &HI), Err(HierarchyError::DuplicateMethods { .. })));
+        assert_eq!(hier.register_methods(InterfaceA::id(), &HI), Err(HierarchyError::UnknownInterface {
+            id: InterfaceA::id(),
+        }));
+
+        let registry = hier.build().unwrap();
+
+        for (id, greeting) in [(Derived::id(), "hello"), (DerivedChild::id(), "hello"), (DerivedGrandchild::id(), "hi")] {
+            assert_eq!(hier.methods::<Greeting>(id).unwrap().0, greeting);
+            assert_eq!(registry.methods::<Greeting>(id).unwrap().0, greeting);
+        }
+
+        assert!(registry.methods::<u32>(Derived::id()).is_none());
+        assert!(registry.methods::<Greeting>(InterfaceA::id()).is_none());
+    }
+
+    #[test]
+    fn dot_export() {
+        let mut hier = Hierarchy::new();
+        hier.register(InterfaceB::id(), None, "InterfaceB").unwrap();
+        hier.register(InterfaceA::id(), Some(InterfaceB::id()), "InterfaceA").unwrap();
+
+        assert_eq!(hier.to_dot(), "\
+digraph interfaces {
+    i13 [label=\"InterfaceA\"];
+    i14 [label=\"InterfaceB\"];
+    i13 -> i14;
+}
+");
     }
 }
@@ -1,153 +1,1078 @@
-use std::cell::Cell;
 use std::ptr::NonNull;
+use std::any::TypeId;
 use std::alloc::Layout;
+use std::mem::ManuallyDrop;
+use std::marker::PhantomData;
 use std::ops::{Deref, DerefMut};
+use std::fmt;
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
 
+use crate::alloc::{Allocator, AllocError};
+use crate::cast::{Interface, InterfaceID};
+use crate::sync::{Counter, Flag, MaybeSync};
+use crate::trace::{self, Trace, Tracer};
+
+// The allocation that a `Dom<T>` points to.
+//
+// The struct is `#[repr(C)]` so that the offset of `value` only depends on the
+// alignment of `T`, see `value_offset`. It also means that the header can be
+// reached without knowing `T`, which is what a `Dom` that has been cast to
+// another interface has to do when it drops the value.
+#[repr(C)]
 struct DomMeta<T> {
-    count: Cell<usize>,
-    value: T,
+    header: Header,
+    value: ManuallyDrop<T>,
+}
+
+// The allocation of a `Dom<T>` that is created with `Dom::new_in`, which is
+// a `DomMeta<T>` followed by the allocator it came from.
+#[repr(C)]
+struct DomMetaIn<T, A> {
+    meta: DomMeta<T>,
+    alloc: ManuallyDrop<A>,
 }
 
+// The part of the allocation that doesn't depend on `T`.
+//
+// `strong` is the number of `Dom<T>` handles and `weak` is the number of
+// `WeakDom<T>` handles, plus one that is collectively held by all the strong
+// handles. The value is dropped when `strong` reaches zero, while the
+// allocation itself is kept around until `weak` also reaches zero, so that
+// the remaining `WeakDom<T>` handles can tell that the value is gone.
+// The top bits of `strong` are used by the cycle collector, see `CLAIMED`.
+//
+// `borrow` keeps track of the borrows that have been handed out through
+// `Dom::borrow` and `Dom::borrow_mut`. It is the number of shared borrows, or
+// `WRITING` if the value is mutably borrowed. The cycle collector sets
+// `SCANNING` while it relies on the handles in the value staying the same.
+//
+// `vtable` is captured when the `Dom` is created, so that the value can be
+// dropped and the allocation freed as the type it was created as, no matter
+// which interface the last handle to it has been cast to.
+//
+// `interface` is the ID of the top-most interface of the value, i.e. the
+// interface it was created as, or 0 until it is known. It is kept out of the
+// value, since the value can be swapped with another through a `&mut` to an
+// interface it inherits from, see `cast.rs`.
+//
+// `buffered` tells if the allocation is a candidate root of the cycle
+// collector, see `trace.rs`.
+pub(crate) struct Header {
+    strong: Counter,
+    weak: Counter,
+    borrow: Counter,
+    vtable: &'static VTable,
+    interface: Counter,
+    buffered: Flag,
+}
+
+const WRITING: usize = usize::MAX;
+const SCANNING: usize = 1 << (usize::BITS - 2);
+
+// Set in `strong` while the cycle collector makes sure that a value is
+// garbage. New strong handles can't be created until it is cleared again.
+pub(crate) const CLAIMED: usize = 1 << (usize::BITS - 2);
+// Set in `strong` when the cycle collector has found that the value is
+// garbage, after which `WeakDom::upgrade` fails.
+pub(crate) const DEAD: usize = 1 << (usize::BITS - 1);
+const COUNT: usize = !(CLAIMED | DEAD);
+
+impl Header {
+    // The header of a value that is being created, which doesn't have any
+    // strong handles yet, see `Dom::init`.
+    fn new(vtable: &'static VTable) -> Header {
+        Header {
+            strong: Counter::new(0),
+            weak: Counter::new(1),
+            borrow: Counter::new(0),
+            vtable,
+            interface: Counter::new(0),
+            buffered: Flag::new(false),
+        }
+    }
+
+    // The strong count, including the bits that are set by the cycle
+    // collector.
+    pub(crate) fn strong_raw(&self) -> &Counter {
+        &self.strong
+    }
+
+    pub(crate) fn strong(&self) -> usize {
+        self.strong.get() & COUNT
+    }
+
+    // Increases the strong count of a value that is known to be alive, e.g.
+    // because there already is a strong handle to it.
+    pub(crate) fn increase_strong(&self) {
+        let mut strong = self.strong.get();
+
+        loop {
+            debug_assert!(strong & COUNT > 0);
+            debug_assert!(strong & COUNT < COUNT - 1);
+
+            if strong & CLAIMED != 0 {
+                std::hint::spin_loop();
+                strong = self.strong.get();
+                continue;
+            }
+
+            match self.strong.compare_exchange(strong, strong + 1) {
+                Ok(_) => return,
+                Err(current) => strong = current,
+            }
+        }
+    }
+
+    // Increases the strong count, unless the value has been dropped.
+    pub(crate) fn try_increase_strong(&self) -> bool {
+        let mut strong = self.strong.get();
+
+        loop {
+            if strong & COUNT == 0 || strong & DEAD != 0 {
+                return false;
+            }
+
+            if strong & CLAIMED != 0 {
+                std::hint::spin_loop();
+                strong = self.strong.get();
+                continue;
+            }
+
+            match self.strong.compare_exchange(strong, strong + 1) {
+                Ok(_) => return true,
+                Err(current) => strong = current,
+            }
+        }
+    }
+
+    // Returns the new strong count, including the bits set by the cycle
+    // collector.
+    fn decrease_strong(&self) -> usize {
+        let strong = self.strong.decrement();
+        debug_assert!(strong & COUNT < COUNT);
+        strong
+    }
+
+    // The number of `WeakDom<T>` handles, plus one if there are any strong
+    // handles.
+    fn weak(&self) -> usize {
+        self.weak.get()
+    }
+
+    fn increase_weak(&self) {
+        let weak = self.weak.increment();
+        debug_assert!(weak > 1);
+        debug_assert!(weak < usize::MAX);
+    }
+
+    fn try_borrow(&self) -> Result<(), BorrowError> {
+        let mut borrow = self.borrow.get();
+
+        loop {
+            if borrow == WRITING || borrow & !SCANNING >= SCANNING - 1 {
+                return Err(BorrowError { _private: () });
+            }
+
+            match self.borrow.compare_exchange(borrow, borrow + 1) {
+                Ok(_) => return Ok(()),
+                Err(current) => borrow = current,
+            }
+        }
+    }
+
+    fn release_borrow(&self) {
+        let borrow = self.borrow.decrement();
+        debug_assert!(borrow & !SCANNING < SCANNING - 1);
+    }
+
+    fn try_borrow_mut(&self) -> Result<(), BorrowMutError> {
+        loop {
+            match self.borrow.compare_exchange(0, WRITING) {
+                Ok(_) => return Ok(()),
+                // The cycle collector only holds on to the value for a short
+                // while, so another thread waits for it rather than failing.
+                Err(SCANNING) if cfg!(feature = "sync") => std::hint::spin_loop(),
+                Err(_) => return Err(BorrowMutError { _private: () }),
+            }
+        }
+    }
+
+    fn release_borrow_mut(&self) {
+        debug_assert!(self.borrow.get() == WRITING);
+        self.borrow.set(0);
+    }
+
+    // Keeps the value from being mutably borrowed until `release_scan`,
+    // unless it already is mutably borrowed.
+    pub(crate) fn try_scan(&self) -> bool {
+        let mut borrow = self.borrow.get();
+
+        loop {
+            if borrow == WRITING {
+                return false;
+            }
+
+            debug_assert!(borrow & SCANNING == 0);
+
+            match self.borrow.compare_exchange(borrow, borrow | SCANNING) {
+                Ok(_) => return true,
+                Err(current) => borrow = current,
+            }
+        }
+    }
+
+    pub(crate) fn release_scan(&self) {
+        let borrow = self.borrow.fetch_and(!SCANNING);
+        debug_assert!(borrow & SCANNING != 0);
+    }
+
+    // Tells if the value was created as a `T`, rather than as an interface
+    // that inherits from `T`.
+    fn created_as<T: 'static>(&self) -> bool {
+        self.vtable.type_id == TypeId::of::<T>()
+    }
+
+    pub(crate) fn interface(&self) -> Option<InterfaceID> {
+        match self.interface.get() {
+            0 => None,
+            id => Some(InterfaceID::new(id as u32)),
+        }
+    }
+
+    // Records the top-most interface of the value, unless it already is, and
+    // returns the one that is recorded.
+    fn record_interface(&self, id: InterfaceID) -> InterfaceID {
+        match self.interface.compare_exchange(0, id.get() as usize) {
+            Ok(_) => id,
+            Err(recorded) => InterfaceID::new(recorded as u32),
+        }
+    }
+
+    // Tells if the value was created with `Dom::new_traced`.
+    pub(crate) fn traced(&self) -> bool {
+        self.vtable.trace.is_some()
+    }
+
+    pub(crate) fn buffered(&self) -> bool {
+        self.buffered.get()
+    }
+
+    pub(crate) fn set_buffered(&self, buffered: bool) {
+        self.buffered.set(buffered);
+    }
+}
+
+impl Header {
+    // Calls `Trace::trace` on the value, which MUST be traced.
+    pub(crate) unsafe fn trace(header: NonNull<Header>, tracer: &mut Tracer) {
+        let trace = header.as_ref().vtable.trace.unwrap();
+        trace(header, tracer);
+    }
+
+    // Drops the value in place, but leaves the allocation.
+    pub(crate) unsafe fn drop_value(header: NonNull<Header>) {
+        #[cfg(feature = "leak-check")]
+        crate::leak::untrack(header);
+
+        (header.as_ref().vtable.drop_value)(header);
+    }
+
+    // Gives up a strong reference. If it was the last one, the value is
+    // dropped as the type it was created as, which isn't `T` if the `Dom` has
+    // been cast.
+    pub(crate) unsafe fn release(header: NonNull<Header>) {
+        let meta = header.as_ref();
+
+        if meta.traced() {
+            if meta.strong.get() > 1 {
+                // The value might only be kept alive by a reference cycle
+                // after this. It has to be buffered while we still hold a
+                // reference, since another thread could otherwise free it.
+                trace::buffer(header);
+            }
+
+            if meta.decrease_strong() == 0 {
+                trace::unbuffer(header);
+                Header::drop_value(header);
+                Header::release_weak(header);
+            }
+        } else if meta.decrease_strong() == 0 {
+            Header::drop_value(header);
+            Header::release_weak(header);
+        }
+    }
+
+    // Gives up a weak reference. If it was the last one, the allocation is
+    // freed, in which case the value MUST already have been dropped.
+    pub(crate) unsafe fn release_weak(header: NonNull<Header>) {
+        let meta = header.as_ref();
+        let weak = meta.weak.decrement();
+        debug_assert!(weak < usize::MAX);
+
+        if weak == 0 {
+            debug_assert!(meta.strong() == 0);
+
+            (meta.vtable.free)(header);
+        }
+    }
+}
+
+// Type-erased drop glue and layout of a `DomMeta<T>`, or a `DomMetaIn<T, A>`.
+pub(crate) struct VTable {
+    drop_value: unsafe fn(NonNull<Header>),
+    // Frees the allocation, after which the header MUST NOT be touched.
+    free: unsafe fn(NonNull<Header>),
+    layout: Layout,
+    // Tells the type of the value apart from other types with the same
+    // layout, e.g. an interface that doesn't add any fields to the one it
+    // inherits from. This is why the values of a `Dom` have to be `'static`.
+    type_id: TypeId,
+    // Only set for values created with `Dom::new_traced`.
+    trace: Option<unsafe fn(NonNull<Header>, &mut Tracer)>,
+}
+
+// NOTE The vtables are stored in associated consts so that a reference to
+//      one is promoted to a `&'static`, one for each type.
+struct VTableFor<T>(PhantomData<T>);
+
+impl<T: 'static> VTableFor<T> {
+    const VTABLE: VTable = VTable {
+        drop_value: drop_value::<T>,
+        free: free::<T>,
+        layout: Layout::new::<DomMeta<T>>(),
+        type_id: TypeId::of::<T>(),
+        trace: None,
+    };
+}
+
+impl<T: Trace + 'static> VTableFor<T> {
+    const TRACED: VTable = VTable {
+        drop_value: drop_value::<T>,
+        free: free::<T>,
+        layout: Layout::new::<DomMeta<T>>(),
+        type_id: TypeId::of::<T>(),
+        trace: Some(trace_value::<T>),
+    };
+}
+
+pub(crate) struct VTableIn<T, A>(PhantomData<(T, A)>);
+
+impl<T: 'static, A: Allocator> VTableIn<T, A> {
+    pub(crate) const VTABLE: VTable = VTable {
+        drop_value: drop_value::<T>,
+        free: free_in::<T, A>,
+        layout: Layout::new::<DomMetaIn<T, A>>(),
+        type_id: TypeId::of::<T>(),
+        trace: None,
+    };
+}
+
+impl<T: Trace + 'static, A: Allocator> VTableIn<T, A> {
+    pub(crate) const TRACED: VTable = VTable {
+        drop_value: drop_value::<T>,
+        free: free_in::<T, A>,
+        layout: Layout::new::<DomMetaIn<T, A>>(),
+        type_id: TypeId::of::<T>(),
+        trace: Some(trace_value::<T>),
+    };
+}
+
+unsafe fn trace_value<T: Trace>(header: NonNull<Header>, tracer: &mut Tracer) {
+    let meta = header.cast::<DomMeta<T>>();
+    meta.as_ref().value.trace(tracer);
+}
+
+unsafe fn drop_value<T>(header: NonNull<Header>) {
+    let mut meta = header.cast::<DomMeta<T>>();
+    ManuallyDrop::drop(&mut meta.as_mut().value);
+}
+
+// NOTE The value isn't touched, since it might never have been written if
+//      `Dom::new_cyclic` panicked.
+unsafe fn free<T>(header: NonNull<Header>) {
+    std::alloc::dealloc(header.as_ptr() as *mut u8, Layout::new::<DomMeta<T>>());
+}
+
+unsafe fn free_in<T, A: Allocator>(header: NonNull<Header>) {
+    let meta = header.cast::<DomMetaIn<T, A>>();
+    let alloc = ManuallyDrop::into_inner(std::ptr::read(&meta.as_ref().alloc));
+
+    // NOTE The allocator is dropped after the allocation is given back, since
+    //      it might be the last handle to a pool that the allocation is in.
+    alloc.deallocate(header.cast(), Layout::new::<DomMetaIn<T, A>>());
+    drop(alloc);
+}
+
+// The offset of the value from the start of the allocation.
+//
+// A `Dom<T>` that is cast to a `Dom<U>` finds the value at the offset for `U`,
+// so the offsets have to be the same. The header is 8-byte aligned, so this
+// holds as long as neither type is aligned to more than 8 bytes, or both are
+// aligned to the same number of bytes.
+const fn value_offset<T>() -> usize {
+    std::mem::offset_of!(DomMeta<T>, value)
+}
+
+// NOTE `#[repr(transparent)]`, so that a `&Dom<T>` can be cast like the handle
+//      itself, see `DomRef::cast_unchecked`.
+#[repr(transparent)]
 pub struct Dom<T> {
     ptr: NonNull<DomMeta<T>>,
 }
 
+// With the `sync` feature the counts and borrows are atomic, so a `Dom<T>` can
+// be shared like an `Arc<T>`. The value is borrowed from several threads at
+// once, which is why `T` has to be `Sync` even to send the handle.
+#[cfg(feature = "sync")]
+unsafe impl<T: Send + Sync> Send for Dom<T> {}
+#[cfg(feature = "sync")]
+unsafe impl<T: Send + Sync> Sync for Dom<T> {}
+
 impl<T> Dom<T> {
     fn meta(&self) -> &DomMeta<T> {
         unsafe { self.ptr.as_ref() }
     }
 
-    fn count(&self) -> usize {
-        self.meta().count.get()
+    pub(crate) fn header(&self) -> NonNull<Header> {
+        self.ptr.cast()
+    }
+
+    // A pointer to the value that is valid even if the value is borrowed.
+    fn value_ptr(&self) -> *const T {
+        // NOTE `ManuallyDrop<T>` has the same layout as `T`.
+        unsafe { std::ptr::addr_of!((*self.ptr.as_ptr()).value) as *const T }
     }
 
     fn increase_count(&self) {
-        let count = self.count();
-        debug_assert!(count > 0);
-        debug_assert!(count < usize::MAX);
-        self.meta().count.set(count + 1);
+        self.meta().header.increase_strong();
+    }
+
+    // Allocates the value with the global allocator.
+    fn allocate(value: T, vtable: &'static VTable) -> Dom<T> {
+        let ptr = Dom::<T>::reserve(vtable);
+        unsafe { Dom::init(ptr, value) }
+    }
+
+    // Allocates the value with the allocator, or gives it back if the
+    // allocator fails.
+    fn allocate_in<A: Allocator>(value: T, vtable: &'static VTable, alloc: A) -> Result<Dom<T>, T> {
+        match Dom::<T>::reserve_in(vtable, alloc) {
+            Ok(ptr) => Ok(unsafe { Dom::init(ptr, value) }),
+            Err(_) => Err(value),
+        }
+    }
+
+    // Allocates the header with the global allocator, and leaves the value
+    // to be written by `init`.
+    fn reserve(vtable: &'static VTable) -> NonNull<DomMeta<T>> {
+        let layout = Layout::new::<DomMeta<T>>();
+        let ptr = NonNull::new(unsafe { std::alloc::alloc(layout) })
+            .unwrap_or_else(|| std::alloc::handle_alloc_error(layout))
+            .cast::<DomMeta<T>>();
+
+        unsafe { std::ptr::addr_of_mut!((*ptr.as_ptr()).header).write(Header::new(vtable)); }
+
+        ptr
     }
 
-    fn decrease_count(&self) {
-        let count = self.count();
-        debug_assert!(count > 0);
-        self.meta().count.set(count - 1);
+    // Like `reserve`, but with the allocator, which is given back if it
+    // fails.
+    fn reserve_in<A: Allocator>(vtable: &'static VTable, alloc: A) -> Result<NonNull<DomMeta<T>>, A> {
+        match alloc.allocate(Layout::new::<DomMetaIn<T, A>>()) {
+            Ok(ptr) => Ok(unsafe { Dom::reserve_at(ptr, vtable, alloc) }),
+            Err(_) => Err(alloc),
+        }
+    }
+
+    // Writes the header and the allocator at the start of the memory.
+    //
+    // SAFETY The memory MUST fit a `DomMetaIn<T, A>`, and MUST stay valid
+    //        until it is given back to the allocator.
+    unsafe fn reserve_at<A: Allocator>(ptr: NonNull<u8>, vtable: &'static VTable, alloc: A) -> NonNull<DomMeta<T>> {
+        let ptr = ptr.cast::<DomMetaIn<T, A>>();
+
+        std::ptr::addr_of_mut!((*ptr.as_ptr()).meta.header).write(Header::new(vtable));
+        std::ptr::addr_of_mut!((*ptr.as_ptr()).alloc).write(ManuallyDrop::new(alloc));
+
+        ptr.cast()
+    }
+
+    // Like `allocate_in`, but the memory comes from `allocate`, and the
+    // allocator only gives it back, see `Arena`.
+    fn allocate_with<A, F>(value: T, vtable: &'static VTable, alloc: A, allocate: F) -> Dom<T>
+    where
+        A: Allocator,
+        F: FnOnce(Layout) -> Result<NonNull<u8>, AllocError>,
+    {
+        let layout = Layout::new::<DomMetaIn<T, A>>();
+        let ptr = allocate(layout).unwrap_or_else(|_| std::alloc::handle_alloc_error(layout));
+
+        unsafe { Dom::init(Dom::reserve_at(ptr, vtable, alloc), value) }
+    }
+
+    // Writes the value in a reserved allocation, and hands out the first
+    // strong handle to it.
+    //
+    // SAFETY The allocation MUST come from `reserve` or `reserve_in`, and
+    //        its value MUST NOT have been written yet.
+    unsafe fn init(ptr: NonNull<DomMeta<T>>, value: T) -> Dom<T> {
+        std::ptr::addr_of_mut!((*ptr.as_ptr()).value).write(ManuallyDrop::new(value));
+        ptr.as_ref().header.strong.set(1);
+
+        Dom::from_allocation(ptr)
+    }
+
+    // Creates the value with a weak handle to itself, which can't be
+    // upgraded until the value has been created.
+    //
+    // NOTE If `data_fn` panics, the allocation is freed once the weak handles
+    //      it gave out are dropped.
+    fn cyclic<F: FnOnce(&WeakDom<T>) -> T>(ptr: NonNull<DomMeta<T>>, data_fn: F) -> Dom<T> {
+        // The weak count of a new allocation is the one that is shared by the
+        // strong handles, which this handle holds until there are any.
+        let weak = WeakDom {
+            ptr,
+        };
+
+        let value = data_fn(&weak);
+        std::mem::forget(weak);
+
+        unsafe { Dom::init(ptr, value) }
+    }
+
+    fn from_allocation(ptr: NonNull<DomMeta<T>>) -> Dom<T> {
+        #[cfg(feature = "leak-check")]
+        crate::leak::track(ptr.cast());
+
+        Dom {
+            ptr,
+        }
     }
 }
 
-impl<T> Dom<T> {
+// NOTE The values are `'static`, since the type they are created as is told
+//      apart by its `TypeId`, see `VTable`.
+impl<T: 'static> Dom<T> {
     pub fn new(value: T) -> Dom<T> {
-        let meta = DomMeta {
-            count: Cell::new(1),
-            value: value,
+        Dom::allocate(value, &VTableFor::<T>::VTABLE)
+    }
+
+    // Creates a value that holds a weak handle to itself, like
+    // `Rc::new_cyclic`. The handle can be stored in the value, but it can't
+    // be upgraded until `new_cyclic` returns.
+    pub fn new_cyclic<F: FnOnce(&WeakDom<T>) -> T>(data_fn: F) -> Dom<T> {
+        Dom::cyclic(Dom::<T>::reserve(&VTableFor::<T>::VTABLE), data_fn)
+    }
+
+    // Returns the value if `this` is the only strong handle to it. The value
+    // has to have been created as a `T`, since unwrapping a `Dom` that has
+    // been cast would lose the rest of the value.
+    // NOTE Any `WeakDom` handles to the value can't be upgraded afterwards.
+    pub fn try_unwrap(this: Dom<T>) -> Result<T, Dom<T>> {
+        let header = this.header();
+        let meta = &this.meta().header;
+
+        if !meta.created_as::<T>() || meta.strong_raw().compare_exchange(1, 0).is_err() {
+            return Err(this);
+        }
+
+        let this = ManuallyDrop::new(this);
+        trace::unbuffer(header);
+
+        #[cfg(feature = "leak-check")]
+        crate::leak::untrack(header);
+
+        unsafe {
+            let value = ManuallyDrop::into_inner(std::ptr::read(&this.meta().value));
+            Header::release_weak(header);
+            Ok(value)
+        }
+    }
+}
+
+impl<T> Dom<T> {
+    // Reinterprets the handle as a handle to a `U`.
+    //
+    // SAFETY The value MUST be a valid `U`, e.g. because `U` is the interface
+    //        of the value or one of the interfaces it inherits from.
+    pub(crate) unsafe fn cast_unchecked<U>(self) -> Dom<U> {
+        const {
+            assert!(
+                value_offset::<T>() == value_offset::<U>(),
+                "Can't cast between types with different alignment requirements"
+            )
         };
 
+        let ptr = self.ptr.cast();
+        std::mem::forget(self);
+
         Dom {
-            ptr: NonNull::from(Box::leak(Box::new(meta))),
+            ptr,
+        }
+    }
+
+    // The top-most interface of the value, see `Header`. It is recorded when
+    // the value is created by `Interface::into_dom`, or else by the first
+    // check on a handle of the type that the value was created as.
+    // Panics if the handle was upcast before then, since the header can't
+    // tell which interface the value was created as.
+    pub(crate) fn top_interface(this: &Dom<T>) -> InterfaceID where T: Interface {
+        let header = &this.meta().header;
+
+        header.interface().unwrap_or_else(|| {
+            assert!(
+                header.created_as::<T>(),
+                "The top-most interface of a value that was upcast before it was checked is unknown, create it with `Interface::into_dom`"
+            );
+
+            header.record_interface(T::id())
+        })
+    }
+
+    // The top-most interface of the value, if it is an interface that has
+    // been recorded, see `top_interface`.
+    pub(crate) fn known_interface(this: &Dom<T>) -> Option<InterfaceID> {
+        this.meta().header.interface()
+    }
+
+    // Creates a weak handle to the value.
+    // NOTE Should be used as an associated function, i.e. `Dom::downgrade(&node)`,
+    //      for the same reason as `Dom::clone`.
+    pub fn downgrade(this: &Dom<T>) -> WeakDom<T> {
+        this.meta().header.increase_weak();
+
+        WeakDom {
+            ptr: this.ptr,
+        }
+    }
+
+    // Immutably borrows the value, the borrow lasts until the returned guard
+    // is dropped. Panics if the value is currently mutably borrowed through
+    // any `Dom` that points to it.
+    pub fn borrow(&self) -> DomRef<'_, T> {
+        self.try_borrow().expect("already mutably borrowed")
+    }
+
+    pub fn try_borrow(&self) -> Result<DomRef<'_, T>, BorrowError> {
+        self.meta().header.try_borrow()?;
+
+        Ok(DomRef {
+            dom: self,
+        })
+    }
+
+    // Mutably borrows the value, the borrow lasts until the returned guard is
+    // dropped. Panics if the value is currently borrowed through any `Dom`
+    // that points to it.
+    pub fn borrow_mut(&self) -> DomRefMut<'_, T> {
+        self.try_borrow_mut().expect("already borrowed")
+    }
+
+    pub fn try_borrow_mut(&self) -> Result<DomRefMut<'_, T>, BorrowMutError> {
+        self.meta().header.try_borrow_mut()?;
+
+        Ok(DomRefMut {
+            dom: self,
+        })
+    }
+
+    /// Returns a mutable reference to the value without checking for other
+    /// borrows.
+    ///
+    /// # Safety
+    ///
+    /// There MUST NOT be any other references to the value while the returned
+    /// reference is alive, e.g. through a clone of `this` or a guard returned
+    /// by `Dom::borrow`.
+    pub unsafe fn get_mut_unchecked(this: &mut Dom<T>) -> &mut T {
+        &mut this.ptr.as_mut().value
+    }
+
+    // Returns a mutable reference to the value if there are no other `Dom` or
+    // `WeakDom` handles to it.
+    pub fn get_mut(this: &mut Dom<T>) -> Option<&mut T> {
+        let meta = &this.meta().header;
+
+        if meta.strong() == 1 && meta.weak() == 1 {
+            // The cycle collector finds candidate roots without holding a
+            // strong reference to them, and could otherwise start tracing the
+            // value while it is mutated through the returned reference.
+            trace::unbuffer(this.header());
+
+            Some(unsafe { Dom::get_mut_unchecked(this) })
+        } else {
+            None
+        }
+    }
+
+    // Tells if the handles point to the same value.
+    pub fn ptr_eq(this: &Dom<T>, other: &Dom<T>) -> bool {
+        this.ptr == other.ptr
+    }
+
+    pub fn strong_count(this: &Dom<T>) -> usize {
+        this.meta().header.strong()
+    }
+
+    // Tells if the cycle collector has found the value to be garbage, in which
+    // case the collector drops it, if it hasn't already.
+    pub(crate) fn is_dead(this: &Dom<T>) -> bool {
+        this.meta().header.strong_raw().get() & DEAD != 0
+    }
+
+    // The number of bytes in the allocation that the value lives in, which
+    // includes the counts and is the size of the type the value was created
+    // as, see `MallocSizeOf`.
+    pub fn allocation_size(this: &Dom<T>) -> usize {
+        this.meta().header.vtable.layout.size()
+    }
+
+    pub fn weak_count(this: &Dom<T>) -> usize {
+        // The strong handles collectively hold one weak reference.
+        this.meta().header.weak() - 1
+    }
+
+    // Consumes the handle without giving up its strong reference, which has
+    // to be given back with `Dom::from_raw` for the value to be dropped.
+    pub fn into_raw(this: Dom<T>) -> *const T {
+        let ptr = this.value_ptr();
+        std::mem::forget(this);
+        ptr
+    }
+
+    /// Takes back a handle that was given up with `Dom::into_raw`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` MUST have been returned by `Dom::into_raw` for a `Dom<T>`, and
+    /// every call to `Dom::into_raw` MUST only be matched by one call to this.
+    pub unsafe fn from_raw(ptr: *const T) -> Dom<T> {
+        let ptr = (ptr as *const u8).sub(value_offset::<T>()) as *mut DomMeta<T>;
+
+        Dom {
+            ptr: NonNull::new_unchecked(ptr),
         }
     }
 }
 
+impl<T: 'static> Dom<T> {
+    // Creates a `Dom` whose allocation comes from `alloc`, see `Allocator`.
+    // Aborts if the allocator fails, like the global allocator would.
+    pub fn new_in<A: Allocator + MaybeSync + 'static>(value: T, alloc: A) -> Dom<T> {
+        Dom::try_new_in(value, alloc)
+            .unwrap_or_else(|_| std::alloc::handle_alloc_error(Layout::new::<DomMetaIn<T, A>>()))
+    }
+
+    // Creates a `Dom` whose allocation comes from `alloc`, or gives back the
+    // value if the allocator fails.
+    pub fn try_new_in<A: Allocator + MaybeSync + 'static>(value: T, alloc: A) -> Result<Dom<T>, T> {
+        Dom::allocate_in(value, &VTableIn::<T, A>::VTABLE, alloc)
+    }
+
+    // Like `Dom::new_in`, but the memory comes from `allocate`, and `alloc`
+    // only gives it back.
+    pub(crate) fn new_with<A, F>(value: T, alloc: A, allocate: F) -> Dom<T>
+    where
+        A: Allocator + MaybeSync + 'static,
+        F: FnOnce(Layout) -> Result<NonNull<u8>, AllocError>,
+    {
+        Dom::allocate_with(value, &VTableIn::<T, A>::VTABLE, alloc, allocate)
+    }
+
+    // Like `Dom::new_cyclic`, with the allocation coming from `alloc`.
+    pub fn new_cyclic_in<A, F>(data_fn: F, alloc: A) -> Dom<T>
+    where
+        A: Allocator + MaybeSync + 'static,
+        F: FnOnce(&WeakDom<T>) -> T,
+    {
+        match Dom::<T>::reserve_in(&VTableIn::<T, A>::VTABLE, alloc) {
+            Ok(ptr) => Dom::cyclic(ptr, data_fn),
+            Err(_) => std::alloc::handle_alloc_error(Layout::new::<DomMetaIn<T, A>>()),
+        }
+    }
+}
+
+// NOTE The cycle collector might trace the value from any thread, so with the
+//      `sync` feature only values that can be shared between threads can be
+//      traced.
+impl<T: Trace + MaybeSync + 'static> Dom<T> {
+    // Creates a `Dom` whose value takes part in cycle collection,
+    // see `collect_cycles`.
+    pub fn new_traced(value: T) -> Dom<T> {
+        Dom::allocate(value, &VTableFor::<T>::TRACED)
+    }
+
+    // Like `Dom::new_in`, for a value that takes part in cycle collection.
+    pub fn new_traced_in<A: Allocator + MaybeSync + 'static>(value: T, alloc: A) -> Dom<T> {
+        Dom::try_new_traced_in(value, alloc)
+            .unwrap_or_else(|_| std::alloc::handle_alloc_error(Layout::new::<DomMetaIn<T, A>>()))
+    }
+
+    pub fn try_new_traced_in<A: Allocator + MaybeSync + 'static>(value: T, alloc: A) -> Result<Dom<T>, T> {
+        Dom::allocate_in(value, &VTableIn::<T, A>::TRACED, alloc)
+    }
+
+    // Like `Dom::new_with`, for a value that takes part in cycle collection.
+    pub(crate) fn new_traced_with<A, F>(value: T, alloc: A, allocate: F) -> Dom<T>
+    where
+        A: Allocator + MaybeSync + 'static,
+        F: FnOnce(Layout) -> Result<NonNull<u8>, AllocError>,
+    {
+        Dom::allocate_with(value, &VTableIn::<T, A>::TRACED, alloc, allocate)
+    }
+}
+
 // NOTE Should preferably be used as an associated function to emphasize that
 //      it is the `Dom` that is cloned and not the `T`,
 //      i.e. `Dom::clone(&node)` instead of `node.clone()`.
 impl<T> Clone for Dom<T> {
         fn clone(&self) -> Dom<T> {
             let dom = Dom {
-                ptr: self.ptr.clone(),
+                ptr: self.ptr,
             };
 
             dom.increase_count();
-            debug_assert!(dom.count() == self.count());
+            debug_assert!(Dom::strong_count(&dom) == Dom::strong_count(self));
 
             dom
         }
 }
 
-impl<T> From<&T> for Dom<T> {
-    fn from(reference: &T) -> Dom<T> {
-        let layout = unsafe {
-            Layout::from_size_align_unchecked(
-                Layout::new::<DomMeta<()>>().size(),
-                std::mem::align_of_val(reference)
-            ).pad_to_align()
-        };
+// There is deliberately no `Deref` or `DerefMut` for `Dom<T>`. Two clones of a
+// `Dom<T>` point to the same value, so it would be possible to aquire a &T and
+// a &mut T to the same value through them, and a plain reference can't tell
+// the `Dom` when it is dropped. Access is instead given out through
+// `Dom::borrow` and `Dom::borrow_mut`, which check the borrows of all the
+// clones at runtime, or through the unsafe `Dom::get_mut_unchecked`.
 
-        let byte_offset = layout.size() as isize;
+// Drops the reference to the value.
+// If this was the last reference,
+// it will also drop the value.
+impl<T> Drop for Dom<T> {
+    fn drop(&mut self) {
+        // The value is dropped as the type it was created as, which isn't `T`
+        // if the `Dom` has been cast.
+        unsafe { Header::release(self.header()); }
+    }
+}
 
-        // SAFETY This is safe as long as the incoming &T came from an existing Dom<T>.
-        let ptr: *mut DomMeta<T> = unsafe {
-            (reference as *const T as *mut u8).offset(-byte_offset) as *mut DomMeta<T>
-        };
+// NOTE The comparisons only look at the pointers, so that they can be done
+//      while the value is mutably borrowed, e.g. by a method on the value
+//      that compares a `Dom` to itself.
+impl<T> PartialEq for Dom<T> {
+    fn eq(&self, other: &Dom<T>) -> bool {
+        self.ptr == other.ptr
+    }
+}
+
+impl<T> Eq for Dom<T> {}
+
+impl<T> PartialEq<T> for Dom<T> {
+    fn eq(&self, other: &T) -> bool {
+        let self_ptr: *const T = self.value_ptr();
+        let other_ptr: *const T = other;
+        self_ptr == other_ptr
+    }
+}
+
+// Handles are ordered and hashed by the address of the value they point to,
+// so that they can be used as keys that identify the value.
+impl<T> Hash for Dom<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.ptr.hash(state);
+    }
+}
+
+impl<T> PartialOrd for Dom<T> {
+    fn partial_cmp(&self, other: &Dom<T>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Dom<T> {
+    fn cmp(&self, other: &Dom<T>) -> Ordering {
+        self.ptr.cmp(&other.ptr)
+    }
+}
+
+// NOTE Only shows the address, since the value might be mutably borrowed.
+impl<T> fmt::Debug for Dom<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Dom").field(&self.value_ptr()).finish()
+    }
+}
+
+// A shared borrow of the value of a `Dom<T>`, see `Dom::borrow`.
+pub struct DomRef<'a, T> {
+    dom: &'a Dom<T>,
+}
+
+impl<T> Deref for DomRef<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &self.dom.ptr.as_ref().value }
+    }
+}
 
-        let dom = Dom {
-            ptr: unsafe { NonNull::new_unchecked(ptr) },
+impl<'a, T> DomRef<'a, T> {
+    // The handle that the value is borrowed through.
+    pub(crate) fn dom(this: &DomRef<'a, T>) -> &'a Dom<T> {
+        this.dom
+    }
+
+    // Reinterprets the borrow as a borrow of a `U`.
+    //
+    // SAFETY Like `Dom::cast_unchecked`.
+    pub(crate) unsafe fn cast_unchecked<U>(this: DomRef<'a, T>) -> DomRef<'a, U> {
+        const {
+            assert!(
+                value_offset::<T>() == value_offset::<U>(),
+                "Can't cast between types with different alignment requirements"
+            )
         };
 
-        dom.increase_count();
+        // The borrow is handed over to the new guard.
+        let this = ManuallyDrop::new(this);
 
-        dom
+        DomRef {
+            dom: &*(this.dom as *const Dom<T> as *const Dom<U>),
+        }
     }
 }
 
-impl<T> Deref for Dom<T> {
+impl<T> Drop for DomRef<'_, T> {
+    fn drop(&mut self) {
+        self.dom.meta().header.release_borrow();
+    }
+}
+
+// A mutable borrow of the value of a `Dom<T>`, see `Dom::borrow_mut`.
+pub struct DomRefMut<'a, T> {
+    dom: &'a Dom<T>,
+}
+
+impl<T> Deref for DomRefMut<'_, T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
-        unsafe { &self.ptr.as_ref().value }
-    }
-}
-
-// SAFETY We have to mutably borrow the Dom<T> to be able to aquire
-//        a mutable reference to the underlying data T.
-//        This means that the normal borrowing rules will apply as long as
-//        we aquire the references through the same Dom<T>,
-//        see multi_mut_same_dom() and ref_and_mut_same_dom().
-//        If we have two Dom<T> copies that point to the same value T,
-//        then it is possible to circumvent the borrowing rules,
-//        e.g. to aquire two &mut T to the same value T,
-//        see multi_mut_different_dom().
-//        In practice, it would be quite unlikely to have to use two copies
-//        of a Dom<T> at the same time.
-// NOTE I will leave the Deref and DerefMut impls as is and later evaluate
-//      whether there is a need for stricter control that guarantees
-//      the XOR rule.
-impl<T> DerefMut for Dom<T> {
+        unsafe { &self.dom.ptr.as_ref().value }
+    }
+}
+
+impl<T> DerefMut for DomRefMut<'_, T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { &mut self.ptr.as_mut().value }
+        unsafe { &mut (*self.dom.ptr.as_ptr()).value }
     }
 }
 
-// Drops the reference to the value.
-// If this was the last reference,
-// it will also drop the value.
-impl<T> Drop for Dom<T> {
+impl<'a, T> DomRefMut<'a, T> {
+    // The handle that the value is borrowed through.
+    pub(crate) fn dom(this: &DomRefMut<'a, T>) -> &'a Dom<T> {
+        this.dom
+    }
+
+    // Reinterprets the borrow as a borrow of a `U`.
+    //
+    // SAFETY Like `Dom::cast_unchecked`.
+    pub(crate) unsafe fn cast_unchecked<U>(this: DomRefMut<'a, T>) -> DomRefMut<'a, U> {
+        const {
+            assert!(
+                value_offset::<T>() == value_offset::<U>(),
+                "Can't cast between types with different alignment requirements"
+            )
+        };
+
+        // The borrow is handed over to the new guard.
+        let this = ManuallyDrop::new(this);
+
+        DomRefMut {
+            dom: &*(this.dom as *const Dom<T> as *const Dom<U>),
+        }
+    }
+}
+
+impl<T> Drop for DomRefMut<'_, T> {
     fn drop(&mut self) {
-        self.decrease_count();
+        self.dom.meta().header.release_borrow_mut();
+    }
+}
 
-        if self.count() == 0 {
-            // Reconstruct the Box from the pointer
-            // we leaked in Dom::new() and imidiately drop it.
-            // NOTE What happens if we reconstruct and drop a Box<T> that
-            // was allocated as a Box<U>?
-            unsafe { Box::from_raw(self.ptr.as_ptr()); }
+// Returned by `Dom::try_borrow` if the value is mutably borrowed.
+#[derive(Debug)]
+pub struct BorrowError {
+    _private: (),
+}
+
+impl fmt::Display for BorrowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "already mutably borrowed")
+    }
+}
+
+impl std::error::Error for BorrowError {}
+
+// Returned by `Dom::try_borrow_mut` if the value is borrowed.
+#[derive(Debug)]
+pub struct BorrowMutError {
+    _private: (),
+}
+
+impl fmt::Display for BorrowMutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "already borrowed")
+    }
+}
+
+impl std::error::Error for BorrowMutError {}
+
+// A handle that doesn't keep the value alive, only the allocation.
+// It is used for back-references, e.g. from a child to its parent, which would
+// otherwise form a reference cycle that is never freed.
+pub struct WeakDom<T> {
+    ptr: NonNull<DomMeta<T>>,
+}
+
+#[cfg(feature = "sync")]
+unsafe impl<T: Send + Sync> Send for WeakDom<T> {}
+#[cfg(feature = "sync")]
+unsafe impl<T: Send + Sync> Sync for WeakDom<T> {}
+
+impl<T> WeakDom<T> {
+    fn meta(&self) -> &DomMeta<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    // Returns a strong handle to the value,
+    // or `None` if the value has already been dropped.
+    pub fn upgrade(&self) -> Option<Dom<T>> {
+        if !self.meta().header.try_increase_strong() {
+            return None;
         }
+
+        Some(Dom {
+            ptr: self.ptr,
+        })
     }
 }
 
-impl<T> PartialEq for Dom<T> {
-    fn eq(&self, other: &Dom<T>) -> bool {
-        let self_ptr: *const T = self.deref();
-        let other_ptr: *const T = other.deref();
-        self_ptr == other_ptr
+impl<T> Clone for WeakDom<T> {
+    fn clone(&self) -> WeakDom<T> {
+        self.meta().header.increase_weak();
+
+        WeakDom {
+            ptr: self.ptr,
+        }
     }
 }
 
-impl<T> PartialEq<T> for Dom<T> {
-    fn eq(&self, other: &T) -> bool {
-        let self_ptr: *const T = self.deref();
-        let other_ptr: *const T = other;
-        self_ptr == other_ptr
+// Drops the reference to the allocation.
+// If this was the last reference, the allocation is freed.
+// The value itself has already been dropped by the last `Dom<T>`.
+impl<T> Drop for WeakDom<T> {
+    fn drop(&mut self) {
+        unsafe { Header::release_weak(self.ptr.cast()); }
     }
 }
 
@@ -160,70 +1085,76 @@ mod tests {
         let dom1 = Dom::new(1_u32);
         let dom2 = Dom::clone(&dom1);
 
-        assert_eq!(dom1.count(), dom2.count());
-        assert_eq!(dom1.count(), 2);
-    }
-
-    #[test]
-    fn from_ref_increases_count() {
-        let dom = Dom::new(1_u32);
-        let r = dom.deref();
-        let dom_from = Dom::from(r);
-        assert_eq!(dom.count(), dom_from.count());
-        assert_eq!(dom.count(), 2);
+        assert_eq!(Dom::strong_count(&dom1), Dom::strong_count(&dom2));
+        assert_eq!(Dom::strong_count(&dom1), 2);
     }
 
     #[test]
     fn multi_mut_same_dom() {
-        let mut dom = Dom::new(1234_u32);
-
-        #[allow(unused_variables)]
-        let x: &mut u32 = dom.deref_mut();
-        let y: &mut u32 = dom.deref_mut();
+        let dom = Dom::new(1234_u32);
 
-        // NOTE As expected, we can't uncomment this,
-        // because that would require us to have two
-        // &mut u32 through the same Dom<u32>,
-        // which isn't possible.
-        //let a = *x;
-        let b = *y;
+        let x = dom.borrow_mut();
 
-        assert_eq!(b, 1234);
+        // NOTE Unlike a plain &mut, the guard doesn't borrow the Dom<u32>
+        // mutably, so the second borrow is caught at runtime instead.
+        assert!(dom.try_borrow_mut().is_err());
+        assert_eq!(*x, 1234);
     }
 
     #[test]
     fn ref_and_mut_same_dom() {
-        let mut dom = Dom::new(1234_u32);
+        let dom = Dom::new(1234_u32);
+
+        let x = dom.borrow();
+        let y = dom.borrow();
 
-        #[allow(unused_variables)]
-        let x: &u32 = dom.deref();
-        let y: &mut u32 = dom.deref_mut();
+        assert!(dom.try_borrow_mut().is_err());
+        assert_eq!(*x, *y);
 
-        // NOTE As expected, we can't uncomment this,
-        // because that would require us to have both a
-        // &u32 and a &mut u32 through the same Dom<u32>,
-        // which isn't possible.
-        //let a = *x;
-        let b = *y;
+        drop(x);
+        drop(y);
 
-        assert_eq!(b, 1234);
+        let mut z = dom.borrow_mut();
+        *z = 4321;
+        assert!(dom.try_borrow().is_err());
+
+        drop(z);
+        assert_eq!(*dom.borrow(), 4321);
     }
 
     #[test]
     fn multi_mut_different_dom() {
-        let mut dom1 = Dom::new(1234_u32);
-        let mut dom2 = Dom::clone(&dom1);
+        let dom1 = Dom::new(1234_u32);
+        let dom2 = Dom::clone(&dom1);
 
-        // NOTE This is possible because
-        // the different references are aquired through
-        // different Dom<u32> copies.
-        let x: &mut u32 = dom1.deref_mut();
-        let y: &mut u32 = dom2.deref_mut();
+        // NOTE The borrow is tracked by the value and not the Dom<u32>,
+        // so a second &mut u32 can't be aquired through another copy.
+        let x = dom1.borrow_mut();
+        assert!(dom2.try_borrow_mut().is_err());
+        assert!(dom2.try_borrow().is_err());
 
-        let a = *x;
-        let b = *y;
+        drop(x);
+        assert!(dom2.try_borrow_mut().is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "already mutably borrowed")]
+    fn borrow_while_mutably_borrowed() {
+        let dom1 = Dom::new(1234_u32);
+        let dom2 = Dom::clone(&dom1);
 
-        assert_eq!(a, b);
+        let _x = dom1.borrow_mut();
+        let _ = dom2.borrow();
+    }
+
+    #[test]
+    fn get_mut_unchecked_same_dom() {
+        let mut dom = Dom::new(1234_u32);
+
+        let x = unsafe { Dom::get_mut_unchecked(&mut dom) };
+        *x = 4321;
+
+        assert_eq!(*dom.borrow(), 4321);
     }
 
     #[test]
@@ -233,4 +1164,195 @@ mod tests {
 
         assert!(dom1 == dom2);
     }
+
+    #[test]
+    fn ptr_eq_and_counts() {
+        let dom1 = Dom::new(1234_u32);
+        let dom2 = Dom::clone(&dom1);
+        let other = Dom::new(1234_u32);
+        let _weak = Dom::downgrade(&dom1);
+
+        assert!(Dom::ptr_eq(&dom1, &dom2));
+        assert!(!Dom::ptr_eq(&dom1, &other));
+        assert_eq!(Dom::strong_count(&dom1), 2);
+        assert_eq!(Dom::weak_count(&dom1), 1);
+        assert_eq!(Dom::weak_count(&other), 0);
+    }
+
+    #[test]
+    fn try_unwrap_unique() {
+        let dom = Dom::new(String::from("value"));
+        let weak = Dom::downgrade(&dom);
+
+        assert_eq!(Dom::try_unwrap(dom).unwrap(), "value");
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn try_unwrap_shared() {
+        let dom = Dom::new(1234_u32);
+        let clone = Dom::clone(&dom);
+
+        let dom = Dom::try_unwrap(dom).unwrap_err();
+        assert!(dom == clone);
+    }
+
+    #[test]
+    fn try_unwrap_after_cast() {
+        #[repr(transparent)]
+        struct Wrapper(u32);
+
+        let dom = Dom::new(Wrapper(1234));
+        let dom: Dom<u32> = unsafe { dom.cast_unchecked() };
+
+        // The value is a `Wrapper`, even though it has the layout of a `u32`.
+        assert!(Dom::try_unwrap(dom).is_err());
+    }
+
+    #[test]
+    fn try_unwrap_same_type_name() {
+        // Reinterprets the handle as a handle to the type of `_like`.
+        unsafe fn cast_like<T, U>(dom: Dom<T>, _like: &U) -> Dom<U> {
+            dom.cast_unchecked()
+        }
+
+        // The closures are different types with the same name.
+        let first = || 1;
+        let second = || 2;
+        assert_eq!(std::any::type_name_of_val(&first), std::any::type_name_of_val(&second));
+
+        let dom = unsafe { cast_like(Dom::new(first), &second) };
+        assert!(Dom::try_unwrap(dom).is_err());
+    }
+
+    #[test]
+    fn get_mut_only_when_unique() {
+        let mut dom = Dom::new(1234_u32);
+        *Dom::get_mut(&mut dom).unwrap() = 4321;
+        assert_eq!(*dom.borrow(), 4321);
+
+        let clone = Dom::clone(&dom);
+        assert!(Dom::get_mut(&mut dom).is_none());
+        drop(clone);
+
+        let weak = Dom::downgrade(&dom);
+        assert!(Dom::get_mut(&mut dom).is_none());
+        drop(weak);
+
+        assert!(Dom::get_mut(&mut dom).is_some());
+    }
+
+    #[test]
+    fn raw_round_trip() {
+        let dom = Dom::new(1234_u32);
+        let clone = Dom::clone(&dom);
+
+        let ptr = Dom::into_raw(clone);
+        assert_eq!(unsafe { *ptr }, 1234);
+        assert_eq!(Dom::strong_count(&dom), 2);
+
+        let clone = unsafe { Dom::from_raw(ptr) };
+        assert!(clone == dom);
+        drop(clone);
+        assert_eq!(Dom::strong_count(&dom), 1);
+    }
+
+    #[test]
+    fn identity_keys() {
+        use std::collections::{BTreeSet, HashMap};
+
+        let dom1 = Dom::new(1234_u32);
+        let dom2 = Dom::new(1234_u32);
+
+        let mut map = HashMap::new();
+        map.insert(Dom::clone(&dom1), "first");
+        map.insert(Dom::clone(&dom2), "second");
+        map.insert(Dom::clone(&dom1), "first again");
+
+        assert_eq!(map.len(), 2);
+        assert_eq!(map[&dom1], "first again");
+
+        let set: BTreeSet<Dom<u32>> = [Dom::clone(&dom2), Dom::clone(&dom1), Dom::clone(&dom2)].into();
+        assert_eq!(set.len(), 2);
+        assert!(format!("{:?}", dom1).starts_with("Dom("));
+    }
+
+    #[test]
+    fn weak_upgrade_while_alive() {
+        let dom = Dom::new(1234_u32);
+        let weak = Dom::downgrade(&dom);
+
+        let upgraded = weak.upgrade();
+        assert!(upgraded.is_some());
+        assert!(upgraded.unwrap() == dom);
+        assert_eq!(Dom::strong_count(&dom), 1);
+    }
+
+    #[test]
+    fn weak_upgrade_after_drop() {
+        let dom = Dom::new(1234_u32);
+        let weak = Dom::downgrade(&dom);
+        let weak_clone = WeakDom::clone(&weak);
+
+        drop(dom);
+
+        assert!(weak.upgrade().is_none());
+        assert!(weak_clone.upgrade().is_none());
+    }
+
+    #[test]
+    fn drop_with_outstanding_weak_drops_value() {
+        let rc = std::rc::Rc::new(());
+        let dom = Dom::new(std::rc::Rc::clone(&rc));
+        let weak = Dom::downgrade(&dom);
+
+        assert_eq!(std::rc::Rc::strong_count(&rc), 2);
+        drop(dom);
+        assert_eq!(std::rc::Rc::strong_count(&rc), 1);
+        drop(weak);
+    }
+
+    #[test]
+    fn new_cyclic_holds_itself() {
+        struct Owner {
+            this: WeakDom<Owner>,
+            value: u32,
+        }
+
+        let dom = Dom::new_cyclic(|this: &WeakDom<Owner>| {
+            // The value doesn't exist yet.
+            assert!(this.upgrade().is_none());
+
+            Owner {
+                this: WeakDom::clone(this),
+                value: 1234,
+            }
+        });
+
+        let this = dom.borrow().this.upgrade().unwrap();
+        assert!(this == dom);
+        assert_eq!(this.borrow().value, 1234);
+        assert_eq!(Dom::strong_count(&dom), 2);
+        assert_eq!(Dom::weak_count(&dom), 1);
+
+        drop(this);
+        let weak = WeakDom::clone(&dom.borrow().this);
+        drop(dom);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn new_cyclic_panics() {
+        let mut kept = None;
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            Dom::<String>::new_cyclic(|this| {
+                kept = Some(WeakDom::clone(this));
+                panic!("not created");
+            })
+        }));
+
+        assert!(result.is_err());
+        assert!(kept.unwrap().upgrade().is_none());
+    }
 }
@@ -0,0 +1,56 @@
+use crate::interface::Node;
+use crate::Interface;
+use crate::{Trace, Tracer};
+use crate::{MallocSizeOf, MemoryReport};
+
+// The base of the nodes that hold text, e.g. `Text`. A `CharacterData` isn't
+// created on its own, only as part of the interfaces that inherit from it.
+#[derive(Interface)]
+#[interface(crate = crate, extends(Node))]
+#[repr(C)]
+pub struct CharacterData {
+    _inherited: Node,
+    data: String,
+}
+
+unsafe impl Trace for CharacterData {
+    fn trace(&self, tracer: &mut Tracer) {
+        self._inherited.trace(tracer);
+    }
+}
+
+// NOTE The data is reported under the top-most interface, like the rest of
+//      the value.
+impl MallocSizeOf for CharacterData {
+    fn shallow_size_of(&self, report: &mut MemoryReport) {
+        self._inherited.shallow_size_of(report);
+        report.add_owned(self.data.capacity());
+    }
+
+    fn deep_size_of(&self, report: &mut MemoryReport) {
+        report.add_owned(self.data.capacity());
+        self._inherited.deep_size_of(report);
+    }
+}
+
+impl CharacterData {
+    pub fn new(data: String) -> Self {
+        CharacterData {
+            _inherited: Node::new(),
+            data,
+        }
+    }
+
+    pub fn data(&self) -> &str {
+        &self.data
+    }
+
+    pub fn set_data(&mut self, data: String) {
+        self.data = data;
+    }
+
+    // The length of the data in UTF-16 code units, as in the DOM standard.
+    pub fn length(&self) -> usize {
+        self.data.encode_utf16().count()
+    }
+}
@@ -1,96 +1,307 @@
-use crate::Dom;
-use crate::Cast;
-use crate::interface::{Node, Element};
-use crate::{Interface, InterfaceID};
-
-use std::ops::{Deref, DerefMut};
+use crate::{Arena, Dom, WeakDom, Registry};
+use crate::interface::{Node, NodeMethods, Element, Text, DocumentFragment, NodeId};
+use crate::interface::node_id::NodeIds;
+use crate::Interface;
+use crate::{Inherits, Upcast, MaybeSync};
+use crate::{Trace, Tracer};
+use crate::{MallocSizeOf, MemoryReport};
 
+// The nodes of the document are allocated in its arena, which is released
+// when the document and all of the nodes are gone.
+//
+// Each node that the document creates, and the document itself, is given a
+// `NodeId` in the slot map of `nodes`.
+//
+// The nodes of the document are told apart by the registry of `nodes`, or by
+// the global registry if the document wasn't created against one of its own.
+// The crate checks them against it, e.g. when they are inserted, whereas the
+// casts of the embedder are given it with `Cast::try_cast_in`.
+#[derive(Interface)]
+#[interface(crate = crate, extends(Node), methods = Document::METHODS)]
 #[repr(C)]
 pub struct Document {
     _inherited: Node,
+    nodes: NodeDocument,
+}
+
+// What the nodes of a document know of it: the slot map that they have an ID
+// in, the registry that they are told apart by, if any, and the arena that
+// the nodes which are created on their behalf, e.g. the text nodes of
+// `ParentNode::append` or the copies of `clone_node`, are allocated in. Each
+// node of the document holds on to it, so that it can still do so once the
+// document is borrowed or gone.
+// NOTE It is handed to `NodeMethods::clone`, so that the copies are created in
+//      the document that they are for, see `NodeDocument::create_node`.
+#[derive(Clone)]
+pub struct NodeDocument {
+    ids: NodeIds,
+    registry: Option<Registry>,
+    arena: Arena,
 }
 
-impl Interface for Document {
-    fn id() -> InterfaceID {
-        InterfaceID::new(2)
+unsafe impl Trace for Document {
+    fn trace(&self, tracer: &mut Tracer) {
+        self._inherited.trace(tracer);
     }
 }
 
-impl Deref for Document {
-    type Target = Node;
+// NOTE The arena isn't reported, since the nodes that are allocated in it
+//      report their own allocations.
+impl MallocSizeOf for Document {
+    fn shallow_size_of(&self, report: &mut MemoryReport) {
+        self._inherited.shallow_size_of(report);
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self._inherited
+    fn deep_size_of(&self, report: &mut MemoryReport) {
+        self._inherited.deep_size_of(report);
     }
 }
 
-impl DerefMut for Document {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self._inherited
+impl Default for Document {
+    fn default() -> Self {
+        Document::new()
     }
 }
 
 impl Document {
     pub fn new() -> Self {
         Document {
-            _inherited: Node::new()
+            _inherited: Node::new(),
+            nodes: NodeDocument::new(),
         }
     }
 
     pub fn create() -> Dom<Self> {
+        Document::allocate(Document::new())
+    }
+
+    // Creates a document whose nodes are told apart by the registry rather
+    // than the global one, see `Registry`.
+    pub fn create_with_registry(registry: Registry) -> Dom<Self> {
         let mut document = Document::new();
+        document.nodes.registry = Some(registry);
+        Document::allocate(document)
+    }
+
+    fn allocate(document: Document) -> Dom<Self> {
+        // The document lives in its own arena as well.
+        let nodes = document.nodes.clone();
+        let document = document.into_dom_in(&nodes.arena);
+        Node::assign_id(&document, &nodes);
+
+        document
+    }
+
+    // A copy of a document is a new document, against the same registry.
+    pub const METHODS: NodeMethods = NodeMethods {
+        node_name: |_| String::from("#document"),
+        clone: |node, _| {
+            let document: Dom<Document> = Node::downcast(node);
+
+            let registry = document.borrow().nodes.registry.clone();
+
+            match registry {
+                Some(registry) => Document::create_with_registry(registry),
+                None => Document::create(),
+            }
+            .upcast()
+        },
+        ..NodeMethods::DEFAULT
+    };
 
-        // Set the appropriate interface ID.
-        unsafe { *std::mem::transmute::<&mut Document, &mut InterfaceID>(&mut document) = Document::id(); }
+    pub fn arena(&self) -> &Arena {
+        self.nodes.arena()
+    }
 
-        Dom::new(document)
+    // The registry that the nodes of the document are told apart by, which
+    // is the global one unless the document was created against another.
+    pub fn registry(&self) -> Option<&Registry> {
+        match self.nodes.registry() {
+            Some(registry) => Some(registry),
+            None => Registry::global(),
+        }
+    }
+
+    // Tells if the node is a `U`, according to the registry of the document.
+    fn node_is<U: Interface>(&self, node: &Dom<Node>) -> bool {
+        match self.nodes.registry() {
+            Some(registry) => node.is_in::<U>(registry),
+            None => node.is::<U>(),
+        }
+    }
+
+    // Creates an element that is allocated in the arena of the document.
+    // NOTE The element isn't inserted into the document.
+    pub fn create_element(&self, local_name: String) -> Dom<Element> {
+        self.nodes.create_element(local_name)
+    }
+
+    // Creates a text node that is allocated in the arena of the document.
+    pub fn create_text_node(&self, data: String) -> Dom<Text> {
+        self.nodes.create_text_node(data)
+    }
+
+    pub fn create_document_fragment(&self) -> Dom<DocumentFragment> {
+        self.nodes.create_document_fragment()
+    }
+
+    // Returns the node with the ID, unless it has been dropped or removed
+    // from the document.
+    pub fn node(&self, id: NodeId) -> Option<Dom<Node>> {
+        let node = self.nodes.ids.get(id)?;
+
+        if node.borrow().root() == self._inherited {
+            Some(node)
+        } else {
+            None
+        }
     }
 
     // Returns the document element, if it exists.
     pub fn element(&self) -> Option<Dom<Element>> {
         let mut curr = self.first_child();
         while let Some(x) = curr {
-            if x.is::<Element>() {
+            if self.node_is::<Element>(&x) {
                 #[cfg(debug_assertions)]
                 {
-                    let mut curr = x.next_sibling();
+                    let mut curr = x.borrow().next_sibling();
                     while let Some(x) = curr {
-                        debug_assert!(!x.is::<Element>());
-                        curr = x.next_sibling();
+                        debug_assert!(!self.node_is::<Element>(&x));
+                        curr = x.borrow().next_sibling();
                     }
                 }
-                return Some(x.cast());
+
+                // SAFETY The node has just been checked to be an `Element`.
+                return Some(unsafe { x.cast_unchecked() });
             }
 
-            curr = x.next_sibling();
+            curr = x.borrow().next_sibling();
         }
 
         None
     }
 }
 
+// The `create_*` methods of `Document`, for the nodes of the document, see
+// `Node::document`.
+impl NodeDocument {
+    fn new() -> Self {
+        NodeDocument {
+            ids: NodeIds::new(),
+            registry: None,
+            arena: Arena::new(),
+        }
+    }
+
+    pub(crate) fn insert(&self, node: WeakDom<Node>) -> NodeId {
+        self.ids.insert(node)
+    }
+
+    pub(crate) fn remove(&self, id: NodeId) {
+        self.ids.remove(id)
+    }
+
+    // The arena that the nodes of the document are allocated in.
+    pub fn arena(&self) -> &Arena {
+        &self.arena
+    }
+
+    // The registry of the document, unless it is the global one.
+    pub(crate) fn registry(&self) -> Option<&Registry> {
+        self.registry.as_ref()
+    }
+
+    // Tells if the handles are to the same document.
+    pub(crate) fn ptr_eq(&self, other: &NodeDocument) -> bool {
+        self.ids.ptr_eq(&other.ids)
+    }
+
+    // Creates a node of any interface that is allocated in the arena of the
+    // document and has an ID in it, e.g. the copy of a node of an interface
+    // of the embedder, see `NodeMethods::clone`.
+    pub fn create_node<T: Inherits<Node> + Trace + MaybeSync>(&self, node: T) -> Dom<T> {
+        let node = node.into_dom_in(&self.arena);
+        Node::assign_id(&node, self);
+        node
+    }
+
+    pub fn create_element(&self, local_name: String) -> Dom<Element> {
+        self.create_node(Element::new(local_name))
+    }
+
+    pub fn create_text_node(&self, data: String) -> Dom<Text> {
+        self.create_node(Text::new(data))
+    }
+
+    pub fn create_document_fragment(&self) -> Dom<DocumentFragment> {
+        self.create_node(DocumentFragment::new())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::tests_init::hierarchy_init;
+    use crate::{Cast, CastIterator};
+    use crate::interface::{AsNode, ParentNode};
 
     #[test]
     fn document_with_no_element() {
         let document = Document::create();
 
-        assert!(document.element().is_none());
+        assert!(document.borrow().element().is_none());
     }
 
     #[test]
     fn document_with_single_element() {
         hierarchy_init();
 
-        let mut document = Document::create();
-        let element = Element::create();
+        let document = Document::create();
+        let element = Element::create(String::from("div"));
+
+        document.borrow_mut().append(element.upcast());
+
+        assert!(document.borrow().element().is_some());
+    }
+
+    #[test]
+    fn document_with_own_registry() {
+        let mut hier = crate::Hierarchy::new();
+        crate::register_interfaces(&mut hier).unwrap();
+        let registry = hier.build().unwrap();
 
-        document.append(element.cast());
+        let document = Document::create_with_registry(registry.clone());
+        let element = document.borrow().create_element(String::from("div"));
+        document.borrow_mut().append(Dom::clone(&element).upcast());
 
-        assert!(document.element().is_some());
+        assert!(document.borrow().registry().unwrap().ptr_eq(&registry));
+        assert!(document.borrow().element().unwrap() == element);
+    }
+
+    // The nodes of the document are told apart by its registry alone, even if
+    // the global one hasn't been initialized.
+    #[test]
+    fn nodes_told_apart_by_own_registry() {
+        let mut hier = crate::Hierarchy::new();
+        crate::register_interfaces(&mut hier).unwrap();
+        let registry = hier.build().unwrap();
+
+        let document = Document::create_with_registry(registry.clone());
+        let element = document.borrow().create_element(String::from("div"));
+        Node::insert(Dom::clone(&element).upcast(), &Dom::clone(&document).upcast(), None);
+
+        // The checks only read the header, so the node can be borrowed.
+        let node: Dom<Node> = Dom::clone(&element).upcast();
+        let _borrow = node.borrow_mut();
+        assert!(node.is_in::<Element>(&registry));
+        let other: Result<Dom<Document>, _> = Dom::clone(&node).try_cast_in(&registry);
+        assert!(other.is_err());
+        drop(_borrow);
+
+        assert_eq!(element.borrow().node_name(), "DIV");
+
+        assert!(document.borrow().element().unwrap() == element);
+        assert_eq!(document.borrow().node_name(), "#document");
     }
 
     #[test]
@@ -98,13 +309,326 @@ mod tests {
     fn document_with_multiple_elements() {
         hierarchy_init();
 
-        let mut document = Document::create();
-        let first_element = Element::create();
-        let second_element = Element::create();
+        let document = Document::create();
+        let first_element = Element::create(String::from("div"));
+        let second_element = Element::create(String::from("div"));
+
+        document.borrow_mut().append(first_element.upcast());
+        document.borrow_mut().append(second_element.upcast());
+
+        assert!(document.borrow().element().is_some());
+    }
+
+    #[test]
+    fn dropping_document_releases_tree() {
+        hierarchy_init();
+
+        let document = Document::create();
+        let element = Element::create(String::from("div"));
+        let child = Element::create(String::from("div"));
+
+        let weak_element = Dom::downgrade(&element);
+        let weak_child = Dom::downgrade(&child);
+
+        element.borrow_mut().append(child.upcast());
+        document.borrow_mut().append(element.upcast());
+
+        assert!(weak_element.upgrade().is_some());
+        assert!(weak_child.upgrade().is_some());
+
+        drop(document);
+
+        assert!(weak_element.upgrade().is_none());
+        assert!(weak_child.upgrade().is_none());
+    }
+
+    #[test]
+    fn elements_are_allocated_in_document_arena() {
+        hierarchy_init();
+
+        let document = Document::create();
+        let before = document.borrow().arena().allocated_bytes();
+
+        for _ in 0..100 {
+            let element = document.borrow().create_element(String::from("div"));
+            document.borrow_mut().append(element.upcast());
+        }
+
+        // The document and the elements all fit in the first chunk.
+        assert_eq!(document.borrow().arena().allocated_bytes(), before);
+        assert!(document.borrow().first_child().unwrap().is::<Element>());
+    }
+
+    #[test]
+    fn copies_are_allocated_in_document_arena() {
+        hierarchy_init();
+
+        let document = Document::create();
+        let element = document.borrow().create_element(String::from("div"));
+
+        // Enough elements to take up more than a chunk of the arena.
+        for _ in 0..1000 {
+            let child = document.borrow().create_element(String::from("p"));
+            element.borrow_mut().append(child.upcast());
+        }
+
+        let before = document.borrow().arena().allocated_bytes();
+        let copy = element.borrow().clone_node(true);
+        assert!(document.borrow().arena().allocated_bytes() > before);
+        assert!(copy.borrow().last_child().unwrap().borrow().node_id().is_some());
+    }
+
+    #[test]
+    fn created_nodes_are_allocated_in_document_arena() {
+        hierarchy_init();
 
-        document.append(first_element.cast());
-        document.append(second_element.cast());
+        let document = Document::create();
+        let element = document.borrow().create_element(String::from("div"));
+        let before = document.borrow().arena().allocated_bytes();
+
+        // Enough text nodes to take up more than a chunk of the arena.
+        element.append(vec!["text"; 1000]).unwrap();
+        let appended = document.borrow().arena().allocated_bytes();
+        assert!(appended > before);
+
+        let copy = element.borrow().clone_node(true);
+        assert!(document.borrow().arena().allocated_bytes() > appended);
+        assert!(copy.borrow().last_child().unwrap().borrow().node_id().is_some());
+    }
+
+    #[test]
+    fn deep_copy_of_document() {
+        hierarchy_init();
+
+        let document = Document::create();
+        let element = document.borrow().create_element(String::from("div"));
+        document.borrow_mut().append(Dom::clone(&element).upcast());
+
+        for _ in 0..1000 {
+            let child = document.borrow().create_element(String::from("p"));
+            element.borrow_mut().append(child.upcast());
+        }
+
+        let before = document.borrow().arena().allocated_bytes();
+
+        // The descendants are created in the copy, rather than in the document
+        // that they are copies of.
+        let copy: Dom<Document> = document.borrow().clone_node(true).cast();
+        assert_eq!(document.borrow().arena().allocated_bytes(), before);
+        assert!(copy.borrow().arena().allocated_bytes() > Document::create().borrow().arena().allocated_bytes());
+
+        let child = copy.borrow().element().unwrap().borrow().last_child().unwrap();
+        assert!(child.borrow().document().unwrap().ptr_eq(&copy.borrow().nodes));
+        assert!(copy.borrow().node(child.borrow().node_id().unwrap()).unwrap() == child);
+    }
+
+    #[test]
+    fn arena_element_outlives_document() {
+        hierarchy_init();
+
+        let document = Document::create();
+        let element = document.borrow().create_element(String::from("div"));
+        let weak_document = Dom::downgrade(&document);
+
+        document.borrow_mut().append(Dom::clone(&element).upcast());
+        drop(document);
+
+        assert!(weak_document.upgrade().is_none());
+        assert!(element.borrow().parent().is_none());
+        assert!(element.is::<Element>());
+    }
+
+    #[test]
+    fn collect_cycles_keeps_document_tree() {
+        hierarchy_init();
+        let _lock = crate::tests_init::collector_lock();
+
+        let document = Document::create();
+        let element = Element::create(String::from("div"));
+        let weak_element = Dom::downgrade(&element);
+
+        document.borrow_mut().append(element.upcast());
+
+        // Dropping the handles that the getters return buffers the elements
+        // as candidate roots, but they are still owned by the document.
+        assert!(document.borrow().element().is_some());
+        assert_eq!(crate::collect_cycles(), 0);
+        assert!(weak_element.upgrade().is_some());
+
+        drop(document);
+        assert!(weak_element.upgrade().is_none());
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn move_document_to_another_thread() {
+        hierarchy_init();
+
+        let document = Document::create();
+        document.borrow_mut().append(Element::create(String::from("div")).upcast());
+
+        let weak_document = Dom::downgrade(&document);
+        let worker = std::thread::spawn(move || {
+            assert!(document.borrow().element().is_some());
+            document
+        });
+
+        let document = worker.join().unwrap();
+        assert!(weak_document.upgrade().unwrap() == document);
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn query_document_from_several_threads() {
+        hierarchy_init();
+
+        let document = Document::create();
+        let element = Element::create(String::from("div"));
+        document.borrow_mut().append(Dom::clone(&element).upcast());
+
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..1000 {
+                        let found = document.borrow().element().unwrap();
+                        assert!(found == element);
+                        assert!(found.borrow().parent().unwrap() == document.as_node());
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn dispatch_on_top_most_interface() {
+        hierarchy_init();
+
+        let document = Document::create();
+        let node: Dom<Node> = Dom::clone(&document).upcast();
+        assert_eq!(node.borrow().node_name(), "#document");
+
+        let copy = node.borrow().clone_node(false);
+        assert!(copy.is::<Document>());
+        assert!(copy != node);
+    }
+
+    #[test]
+    fn clone_subtree() {
+        hierarchy_init();
+
+        let document = Document::create();
+        let element = document.borrow().create_element(String::from("div"));
+        element.borrow_mut().append(document.borrow().create_element(String::from("p")).upcast());
+        element.borrow_mut().append(document.borrow().create_element(String::from("span")).upcast());
+        document.borrow_mut().append(Dom::clone(&element).upcast());
+
+        let shallow = element.borrow().clone_node(false);
+        assert!(shallow.is::<Element>());
+        assert!(shallow.borrow().first_child().is_none());
+
+        let copy = element.borrow().clone_node(true);
+        assert!(copy.borrow().parent().is_none());
+        assert!(copy.borrow().node_id().is_some());
+        assert!(copy.borrow().node_id() != element.borrow().node_id());
+        assert_eq!(copy.borrow().node_name(), "DIV");
+
+        let children: Vec<Dom<Element>> = std::iter::successors(copy.borrow().first_child(), |child| child.borrow().next_sibling())
+            .filter_cast::<Element>()
+            .collect();
+        assert_eq!(children.len(), 2);
+        assert_eq!(children[0].borrow().local_name(), "p");
+        assert_eq!(children[1].borrow().local_name(), "span");
+        assert!(children.iter().all(|child| child.borrow().parent().unwrap() == copy));
+        assert!(element.borrow().first_child().unwrap() != children[0].as_node());
+
+        // The copies belong to the document once they are inserted.
+        document.borrow_mut().append(Dom::clone(&copy));
+        assert!(document.borrow().node(children[1].borrow().node_id().unwrap()).is_some());
+    }
+
+    #[test]
+    fn node_by_id() {
+        hierarchy_init();
+
+        let document = Document::create();
+        let element = document.borrow().create_element(String::from("div"));
+        document.borrow_mut().append(Dom::clone(&element).upcast());
+
+        let id = element.borrow().node_id().unwrap();
+        assert!(document.borrow().node(id).unwrap() == element.upcast());
+        assert!(document.borrow().node(document.borrow().node_id().unwrap()).unwrap() == document.as_node());
+        assert!(document.borrow().node(NodeId::from_bits(id.to_bits())).is_some());
+
+        // Nodes that aren't created by a document don't have an ID, until
+        // they are inserted into one.
+        assert!(Element::create(String::from("div")).borrow().node_id().is_none());
+    }
+
+    #[test]
+    fn stale_node_id() {
+        hierarchy_init();
+
+        let document = Document::create();
+        let element = document.borrow().create_element(String::from("div"));
+        let id = element.borrow().node_id().unwrap();
+
+        drop(element);
+        assert!(document.borrow().node(id).is_none());
+
+        // The slot is reused by the next node, but the old ID doesn't find it.
+        let element = document.borrow().create_element(String::from("div"));
+        document.borrow_mut().append(Dom::clone(&element).upcast());
+
+        assert!(element.borrow().node_id().unwrap() != id);
+        assert!(document.borrow().node(id).is_none());
+        assert!(document.borrow().node(element.borrow().node_id().unwrap()).is_some());
+    }
+
+    #[test]
+    fn removed_node_id() {
+        hierarchy_init();
+
+        let document = Document::create();
+        let element = document.borrow().create_element(String::from("div"));
+        let id = element.borrow().node_id().unwrap();
+
+        // A node that isn't in the document can't be found.
+        assert!(document.borrow().node(id).is_none());
+
+        document.borrow_mut().append(Dom::clone(&element).upcast());
+        assert!(document.borrow().node(id).is_some());
+
+        element.borrow_mut().detach();
+        assert!(document.borrow().node(id).is_none());
+
+        // The node is given an ID in the document that it is moved to, and
+        // the old ID is gone.
+        let other = Document::create();
+        other.borrow_mut().append(Dom::clone(&element).upcast());
+        let other_id = element.borrow().node_id().unwrap();
+
+        assert!(other.borrow().node(other_id).unwrap() == element.as_node());
+        assert!(document.borrow().node(id).is_none());
+
+        element.borrow_mut().detach();
+        document.borrow_mut().append(Dom::clone(&element).upcast());
+        assert!(other.borrow().node(other_id).is_none());
+    }
+
+    #[test]
+    fn inserted_node_id() {
+        hierarchy_init();
+
+        let document = Document::create();
+        let element = Element::create(String::from("div"));
+        let child = Element::create(String::from("div"));
+        element.borrow_mut().append(Dom::clone(&child).upcast());
+        assert!(child.borrow().node_id().is_none());
 
-        assert!(document.element().is_some());
+        // The node and its descendants join the document that they are
+        // inserted into.
+        document.borrow_mut().append(Dom::clone(&element).upcast());
+        let id = child.borrow().node_id().unwrap();
+        assert!(document.borrow().node(id).unwrap() == child.as_node());
     }
 }
@@ -0,0 +1,63 @@
+use crate::{Arena, Dom};
+use crate::interface::{Node, NodeMethods};
+use crate::Interface;
+use crate::Upcast;
+use crate::{Trace, Tracer};
+use crate::{MallocSizeOf, MemoryReport};
+
+// A node without a parent that holds other nodes until they are inserted
+// somewhere else, at which point its children are inserted in its place.
+#[derive(Interface)]
+#[interface(crate = crate, extends(Node), methods = DocumentFragment::METHODS)]
+#[repr(C)]
+pub struct DocumentFragment {
+    _inherited: Node,
+}
+
+unsafe impl Trace for DocumentFragment {
+    fn trace(&self, tracer: &mut Tracer) {
+        self._inherited.trace(tracer);
+    }
+}
+
+impl MallocSizeOf for DocumentFragment {
+    fn shallow_size_of(&self, report: &mut MemoryReport) {
+        self._inherited.shallow_size_of(report);
+    }
+
+    fn deep_size_of(&self, report: &mut MemoryReport) {
+        self._inherited.deep_size_of(report);
+    }
+}
+
+impl Default for DocumentFragment {
+    fn default() -> Self {
+        DocumentFragment::new()
+    }
+}
+
+impl DocumentFragment {
+    pub fn new() -> Self {
+        DocumentFragment {
+            _inherited: Node::new(),
+        }
+    }
+
+    pub fn create() -> Dom<Self> {
+        DocumentFragment::new().into_dom()
+    }
+
+    // Creates the fragment in an arena, e.g. the one of a document.
+    pub fn create_in(arena: &Arena) -> Dom<Self> {
+        DocumentFragment::new().into_dom_in(arena)
+    }
+
+    pub const METHODS: NodeMethods = NodeMethods {
+        node_name: |_| String::from("#document-fragment"),
+        clone: |_, document| match document {
+            Some(document) => document.create_document_fragment().upcast(),
+            None => DocumentFragment::create().upcast(),
+        },
+        ..NodeMethods::DEFAULT
+    };
+}
@@ -0,0 +1,32 @@
+use std::fmt;
+
+// The errors that the DOM standard throws as a `DOMException`, by the name of
+// the exception.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DomException {
+    // The operation would yield an incorrect node tree, along with why.
+    HierarchyRequestError(&'static str),
+}
+
+impl DomException {
+    // The name of the exception, as in the DOM standard.
+    pub fn name(&self) -> &'static str {
+        match self {
+            DomException::HierarchyRequestError(_) => "HierarchyRequestError",
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            DomException::HierarchyRequestError(message) => message,
+        }
+    }
+}
+
+impl fmt::Display for DomException {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.name(), self.message())
+    }
+}
+
+impl std::error::Error for DomException {}
@@ -1,47 +1,82 @@
-use crate::Dom;
-use crate::interface::Node;
-use crate::{Interface, InterfaceID};
-
-use std::ops::{Deref, DerefMut};
+use crate::{Arena, Dom};
+use crate::interface::{Node, NodeMethods};
+use crate::Interface;
+use crate::Upcast;
+use crate::{Trace, Tracer};
+use crate::{MallocSizeOf, MemoryReport};
 
+#[derive(Interface)]
+#[interface(crate = crate, extends(Node), methods = Element::METHODS)]
 #[repr(C)]
 pub struct Element {
     _inherited: Node,
+    local_name: String,
 }
 
-impl Interface for Element {
-    fn id() -> InterfaceID {
-        InterfaceID::new(3)
+unsafe impl Trace for Element {
+    fn trace(&self, tracer: &mut Tracer) {
+        self._inherited.trace(tracer);
     }
 }
 
-impl Deref for Element {
-    type Target = Node;
-
-    fn deref(&self) -> &Self::Target {
-        &self._inherited
+// NOTE The local name is reported under the top-most interface, like the rest
+//      of the value.
+impl MallocSizeOf for Element {
+    fn shallow_size_of(&self, report: &mut MemoryReport) {
+        self._inherited.shallow_size_of(report);
+        report.add_owned(self.local_name.capacity());
     }
-}
 
-impl DerefMut for Element {
-    fn deref_mut(&mut self)  -> &mut Self::Target {
-        &mut self._inherited
+    fn deep_size_of(&self, report: &mut MemoryReport) {
+        report.add_owned(self.local_name.capacity());
+        self._inherited.deep_size_of(report);
     }
 }
 
 impl Element {
-    pub fn new() -> Self {
+    pub fn new(local_name: String) -> Self {
         Element {
             _inherited: Node::new(),
+            local_name,
         }
     }
 
-    pub fn create() -> Dom<Self> {
-        let mut element = Element::new();
+    pub fn create(local_name: String) -> Dom<Self> {
+        Element::new(local_name).into_dom()
+    }
 
-        // Set the appropriate interface ID.
-        unsafe { *std::mem::transmute::<&mut Element, &mut InterfaceID>(&mut element) = Element::id(); }
+    // Creates the element in an arena, e.g. the one of a document.
+    pub fn create_in(arena: &Arena, local_name: String) -> Dom<Self> {
+        Element::new(local_name).into_dom_in(arena)
+    }
 
-        Dom::new(element)
+    pub fn local_name(&self) -> &str {
+        &self.local_name
     }
+
+    // The node name of an element is its name in uppercase, as for the
+    // elements of an HTML document.
+    // NOTE Elements don't have a namespace nor a prefix yet, so the name is
+    //      the local name.
+    pub const METHODS: NodeMethods = NodeMethods {
+        node_name: |node| {
+            let element: Dom<Element> = Node::downcast(node);
+            let name = element.borrow().local_name().to_ascii_uppercase();
+            name
+        },
+        clone: |node, document| {
+            let element: Dom<Element> = Node::downcast(node);
+            let local_name = element.borrow().local_name().to_owned();
+
+            match document {
+                Some(document) => document.create_element(local_name).upcast(),
+                None => Element::create(local_name).upcast(),
+            }
+        },
+        shallow_size_of: |node, report| {
+            let element: Dom<Element> = Node::downcast(node);
+            element.shallow_size_of(report);
+        },
+        ..NodeMethods::DEFAULT
+    };
 }
@@ -0,0 +1,524 @@
+use crate::{Dom, Registry};
+use crate::interface::{Node, Document, DocumentFragment, Element, CharacterData, Text};
+use crate::interface::DomException;
+use crate::{Interface, Inherits, Upcast, CastIterator, FilterCast};
+
+// The mixins of the DOM standard that are shared by several interfaces, e.g.
+// `ParentNode` by `Document`, `DocumentFragment` and `Element`.
+//
+// The mixins are implemented on the handles of the interfaces rather than on
+// the interfaces themselves, since they change the links of the node that
+// they are called on, which takes a `Dom::borrow_mut` of it. Like the rest of
+// the tree, they panic if one of the nodes involved is already borrowed.
+//
+// The nodes are told apart by the registry of the document of the node that
+// the mixin is called on, see `Node::registry`.
+
+// The argument of the mixin methods that take nodes, where a string stands
+// for a `Text` node with the string as its data.
+pub enum NodeOrString {
+    Node(Dom<Node>),
+    String(String),
+}
+
+impl<T: Inherits<Node>> From<Dom<T>> for NodeOrString {
+    fn from(node: Dom<T>) -> Self {
+        NodeOrString::Node(node.upcast())
+    }
+}
+
+impl From<String> for NodeOrString {
+    fn from(data: String) -> Self {
+        NodeOrString::String(data)
+    }
+}
+
+impl From<&str> for NodeOrString {
+    fn from(data: &str) -> Self {
+        NodeOrString::String(data.to_owned())
+    }
+}
+
+// The handle of an interface that inherits from `Node`, as a `Dom<Node>`.
+pub trait AsNode {
+    fn as_node(&self) -> Dom<Node>;
+}
+
+impl<T: Inherits<Node>> AsNode for Dom<T> {
+    fn as_node(&self) -> Dom<Node> {
+        Dom::clone(self).upcast()
+    }
+}
+
+pub trait ParentNode: AsNode {
+    // The children of the node that are elements.
+    fn children(&self) -> Vec<Dom<Element>> {
+        let node = self.as_node();
+        elements(children_of(&node), registry_of(&node).as_ref()).collect()
+    }
+
+    fn first_element_child(&self) -> Option<Dom<Element>> {
+        let node = self.as_node();
+        elements(children_of(&node), registry_of(&node).as_ref()).next()
+    }
+
+    fn last_element_child(&self) -> Option<Dom<Element>> {
+        let node = self.as_node();
+        let children = std::iter::successors(node.borrow().last_child(), |child| child.borrow().previous_sibling());
+        elements(children, registry_of(&node).as_ref()).next()
+    }
+
+    fn child_element_count(&self) -> usize {
+        let node = self.as_node();
+        elements(children_of(&node), registry_of(&node).as_ref()).count()
+    }
+
+    // Inserts the nodes before the first child of the node.
+    fn prepend<I>(&self, nodes: I) -> Result<(), DomException>
+    where
+        I: IntoIterator,
+        I::Item: Into<NodeOrString>,
+    {
+        let parent = self.as_node();
+        let node = convert(nodes.into_iter().map(Into::into).collect(), &parent)?;
+        let child = parent.borrow().first_child();
+
+        pre_insert(node, &parent, child)
+    }
+
+    // Inserts the nodes after the last child of the node.
+    fn append<I>(&self, nodes: I) -> Result<(), DomException>
+    where
+        I: IntoIterator,
+        I::Item: Into<NodeOrString>,
+    {
+        let parent = self.as_node();
+        let node = convert(nodes.into_iter().map(Into::into).collect(), &parent)?;
+
+        pre_insert(node, &parent, None)
+    }
+
+    // Replaces all of the children of the node with the nodes.
+    fn replace_children<I>(&self, nodes: I) -> Result<(), DomException>
+    where
+        I: IntoIterator,
+        I::Item: Into<NodeOrString>,
+    {
+        let parent = self.as_node();
+        let node = convert(nodes.into_iter().map(Into::into).collect(), &parent)?;
+        ensure_pre_insert_validity(&node, &parent, None)?;
+
+        for child in children_of(&parent).collect::<Vec<_>>() {
+            child.borrow_mut().detach();
+        }
+
+        insert(node, &parent, None);
+        Ok(())
+    }
+}
+
+impl ParentNode for Dom<Document> {}
+impl ParentNode for Dom<DocumentFragment> {}
+impl ParentNode for Dom<Element> {}
+
+// NOTE The methods do nothing if the node doesn't have a parent.
+pub trait ChildNode: AsNode {
+    // Inserts the nodes before the node.
+    fn before<I>(&self, nodes: I) -> Result<(), DomException>
+    where
+        I: IntoIterator,
+        I::Item: Into<NodeOrString>,
+    {
+        let this = self.as_node();
+        let Some(parent) = this.borrow().parent() else {
+            return Ok(());
+        };
+
+        let nodes: Vec<NodeOrString> = nodes.into_iter().map(Into::into).collect();
+        let viable_previous = std::iter::successors(this.borrow().previous_sibling(), |sibling| sibling.borrow().previous_sibling())
+            .find(|sibling| !contains(&nodes, sibling));
+
+        let node = convert(nodes, &this)?;
+
+        // The viable sibling is only followed once the nodes have been taken
+        // out of the tree by the conversion.
+        let child = match viable_previous {
+            Some(previous) => previous.borrow().next_sibling(),
+            None => parent.borrow().first_child(),
+        };
+
+        pre_insert(node, &parent, child)
+    }
+
+    // Inserts the nodes after the node.
+    fn after<I>(&self, nodes: I) -> Result<(), DomException>
+    where
+        I: IntoIterator,
+        I::Item: Into<NodeOrString>,
+    {
+        let this = self.as_node();
+        let Some(parent) = this.borrow().parent() else {
+            return Ok(());
+        };
+
+        let nodes: Vec<NodeOrString> = nodes.into_iter().map(Into::into).collect();
+        let viable_next = following_siblings(&this).find(|sibling| !contains(&nodes, sibling));
+        let node = convert(nodes, &this)?;
+
+        pre_insert(node, &parent, viable_next)
+    }
+
+    // Replaces the node with the nodes, which may include the node itself.
+    fn replace_with<I>(&self, nodes: I) -> Result<(), DomException>
+    where
+        I: IntoIterator,
+        I::Item: Into<NodeOrString>,
+    {
+        let this = self.as_node();
+        let Some(parent) = this.borrow().parent() else {
+            return Ok(());
+        };
+
+        let nodes: Vec<NodeOrString> = nodes.into_iter().map(Into::into).collect();
+        let viable_next = following_siblings(&this).find(|sibling| !contains(&nodes, sibling));
+        let node = convert(nodes, &this)?;
+
+        // The node has been moved into a fragment if there were several nodes,
+        // and it was one of them.
+        if this.borrow().parent().as_ref() == Some(&parent) {
+            replace(&this, node, &parent)
+        } else {
+            pre_insert(node, &parent, viable_next)
+        }
+    }
+
+    // Removes the node from its parent.
+    fn remove(&self) {
+        self.as_node().borrow_mut().detach();
+    }
+}
+
+impl ChildNode for Dom<Element> {}
+impl ChildNode for Dom<CharacterData> {}
+impl ChildNode for Dom<Text> {}
+
+pub trait NonDocumentTypeChildNode: AsNode {
+    fn previous_element_sibling(&self) -> Option<Dom<Element>> {
+        let node = self.as_node();
+        let siblings = std::iter::successors(node.borrow().previous_sibling(), |sibling| sibling.borrow().previous_sibling());
+        elements(siblings, registry_of(&node).as_ref()).next()
+    }
+
+    fn next_element_sibling(&self) -> Option<Dom<Element>> {
+        let node = self.as_node();
+        elements(following_siblings(&node), registry_of(&node).as_ref()).next()
+    }
+}
+
+impl NonDocumentTypeChildNode for Dom<Element> {}
+impl NonDocumentTypeChildNode for Dom<CharacterData> {}
+impl NonDocumentTypeChildNode for Dom<Text> {}
+
+// The registry of the document of the node, unless it is the global one.
+fn registry_of(node: &Dom<Node>) -> Option<Registry> {
+    node.borrow().document_registry().cloned()
+}
+
+// Tells if the node is a `U`, according to the registry, if any, or else the
+// global hierarchy.
+fn is<U: Interface>(node: &Dom<Node>, registry: Option<&Registry>) -> bool {
+    match registry {
+        Some(registry) => node.is_in::<U>(registry),
+        None => node.is::<U>(),
+    }
+}
+
+// The nodes that are elements, according to the registry, if any.
+fn elements<I: Iterator<Item = Dom<Node>>>(nodes: I, registry: Option<&Registry>) -> FilterCast<I, Element> {
+    match registry {
+        Some(registry) => nodes.filter_cast_in(registry),
+        None => nodes.filter_cast(),
+    }
+}
+
+fn children_of(node: &Dom<Node>) -> impl Iterator<Item = Dom<Node>> {
+    std::iter::successors(node.borrow().first_child(), |child| child.borrow().next_sibling())
+}
+
+fn following_siblings(node: &Dom<Node>) -> impl Iterator<Item = Dom<Node>> {
+    std::iter::successors(node.borrow().next_sibling(), |sibling| sibling.borrow().next_sibling())
+}
+
+fn contains(nodes: &[NodeOrString], node: &Dom<Node>) -> bool {
+    nodes.iter().any(|other| matches!(other, NodeOrString::Node(other) if other == node))
+}
+
+// Converts the nodes into a single node: the node itself if there is only
+// one, or else a fragment that they are moved into. The strings become `Text`
+// nodes, which are created by the document of `this` along with the fragment.
+fn convert(nodes: Vec<NodeOrString>, this: &Dom<Node>) -> Result<Dom<Node>, DomException> {
+    let document = this.borrow().document().cloned();
+
+    let mut nodes: Vec<Dom<Node>> = nodes.into_iter()
+        .map(|node| match node {
+            NodeOrString::Node(node) => node,
+            NodeOrString::String(data) => match &document {
+                Some(document) => document.create_text_node(data).upcast(),
+                None => Text::create(data).upcast(),
+            },
+        })
+        .collect();
+
+    if nodes.len() == 1 {
+        return Ok(nodes.pop().unwrap());
+    }
+
+    let fragment: Dom<Node> = match &document {
+        Some(document) => document.create_document_fragment().upcast(),
+        None => DocumentFragment::create().upcast(),
+    };
+
+    for node in nodes {
+        pre_insert(node, &fragment, None)?;
+    }
+
+    Ok(fragment)
+}
+
+// Checks that inserting `node` into `parent` yields a valid tree. `replaced`
+// is the child that `node` replaces, if any.
+fn ensure_pre_insert_validity(node: &Dom<Node>, parent: &Dom<Node>, replaced: Option<&Dom<Node>>) -> Result<(), DomException> {
+    let registry = registry_of(parent);
+    let registry = registry.as_ref();
+
+    if !is::<Document>(parent, registry) && !is::<DocumentFragment>(parent, registry) && !is::<Element>(parent, registry) {
+        return Err(DomException::HierarchyRequestError("Only a document, a document fragment or an element can have children"));
+    }
+
+    let mut ancestor = Some(Dom::clone(parent));
+
+    while let Some(curr) = ancestor {
+        if curr == *node {
+            return Err(DomException::HierarchyRequestError("The node is an ancestor of the parent"));
+        }
+
+        ancestor = curr.borrow().parent();
+    }
+
+    if !is::<DocumentFragment>(node, registry) && !is::<Element>(node, registry) && !is::<CharacterData>(node, registry) {
+        return Err(DomException::HierarchyRequestError("The node can't be a child"));
+    }
+
+    if is::<Document>(parent, registry) {
+        if is::<Text>(node, registry) || (is::<DocumentFragment>(node, registry) && children_of(node).any(|child| is::<Text>(&child, registry))) {
+            return Err(DomException::HierarchyRequestError("A document can't have text children"));
+        }
+
+        let elements = if is::<DocumentFragment>(node, registry) {
+            children_of(node).filter(|child| is::<Element>(child, registry)).count()
+        } else {
+            is::<Element>(node, registry) as usize
+        };
+
+        let has_element = children_of(parent).any(|child| is::<Element>(&child, registry) && Some(&child) != replaced);
+
+        if elements > 1 || (elements == 1 && has_element) {
+            return Err(DomException::HierarchyRequestError("A document can only have one element child"));
+        }
+    }
+
+    Ok(())
+}
+
+// Inserts `node` into `parent` before `child`, or as the last child.
+fn pre_insert(node: Dom<Node>, parent: &Dom<Node>, child: Option<Dom<Node>>) -> Result<(), DomException> {
+    ensure_pre_insert_validity(&node, parent, None)?;
+
+    let child = match child {
+        Some(child) if child == node => node.borrow().next_sibling(),
+        child => child,
+    };
+
+    insert(node, parent, child);
+    Ok(())
+}
+
+// Replaces `child` of `parent` with `node`.
+fn replace(child: &Dom<Node>, node: Dom<Node>, parent: &Dom<Node>) -> Result<(), DomException> {
+    ensure_pre_insert_validity(&node, parent, Some(child))?;
+
+    let reference = match child.borrow().next_sibling() {
+        Some(next) if next == node => node.borrow().next_sibling(),
+        next => next,
+    };
+
+    child.borrow_mut().detach();
+    insert(node, parent, reference);
+    Ok(())
+}
+
+// Moves `node` into the tree, or the children of `node` if it is a fragment.
+// The insertion steps of the nodes are run once all of them are in the tree,
+// and none of the nodes are borrowed anymore.
+fn insert(node: Dom<Node>, parent: &Dom<Node>, child: Option<Dom<Node>>) {
+    let nodes: Vec<Dom<Node>> = if is::<DocumentFragment>(&node, registry_of(parent).as_ref()) {
+        children_of(&node).collect()
+    } else {
+        vec![node]
+    };
+
+    for node in &nodes {
+        node.borrow_mut().detach();
+
+        match &child {
+            Some(child) => child.borrow_mut().insert_before(Dom::clone(node)),
+            None => parent.borrow_mut().append(Dom::clone(node)),
+        }
+    }
+
+    for node in &nodes {
+        Node::inserted(node);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cast;
+    use crate::tests_init::hierarchy_init;
+
+    // The children of the node, with the text nodes as their data.
+    fn describe(node: &Dom<Node>) -> Vec<String> {
+        children_of(node)
+            .map(|child| match Cast::<Node, Text>::try_cast(child) {
+                Ok(text) => text.borrow().data().to_owned(),
+                Err(child) => child.into_inner().borrow().node_name(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parent_node_queries() {
+        hierarchy_init();
+
+        let parent = Element::create(String::from("div"));
+        let first = Element::create(String::from("p"));
+        let last = Element::create(String::from("span"));
+        parent.append(["a".into(), NodeOrString::from(Dom::clone(&first)), "b".into(), Dom::clone(&last).into(), "c".into()]).unwrap();
+
+        assert_eq!(describe(&parent.as_node()), ["a", "P", "b", "SPAN", "c"]);
+        assert_eq!(parent.child_element_count(), 2);
+        assert!(parent.children() == [Dom::clone(&first), Dom::clone(&last)]);
+        assert!(parent.first_element_child().unwrap() == first);
+        assert!(parent.last_element_child().unwrap() == last);
+
+        assert!(first.previous_element_sibling().is_none());
+        assert!(first.next_element_sibling().unwrap() == last);
+        assert!(last.previous_element_sibling().unwrap() == first);
+        assert!(last.next_element_sibling().is_none());
+    }
+
+    #[test]
+    fn prepend_and_replace_children() {
+        hierarchy_init();
+
+        let parent = Element::create(String::from("div"));
+        parent.append(["b"]).unwrap();
+        parent.prepend(["a"]).unwrap();
+        assert_eq!(describe(&parent.as_node()), ["a", "b"]);
+
+        parent.replace_children(["c", "d"]).unwrap();
+        assert_eq!(describe(&parent.as_node()), ["c", "d"]);
+
+        parent.replace_children(Vec::<NodeOrString>::new()).unwrap();
+        assert!(parent.borrow().first_child().is_none());
+    }
+
+    #[test]
+    fn child_node_moves() {
+        hierarchy_init();
+
+        let parent = Element::create(String::from("div"));
+        let a = Text::create(String::from("a"));
+        let b = Text::create(String::from("b"));
+        let c = Text::create(String::from("c"));
+        parent.append([Dom::clone(&a), Dom::clone(&b), Dom::clone(&c)]).unwrap();
+
+        b.before(["x"]).unwrap();
+        assert_eq!(describe(&parent.as_node()), ["a", "x", "b", "c"]);
+
+        // The nodes can include the siblings, and the node itself.
+        b.after([Dom::clone(&a)]).unwrap();
+        assert_eq!(describe(&parent.as_node()), ["x", "b", "a", "c"]);
+
+        b.before([Dom::clone(&c), Dom::clone(&b)]).unwrap();
+        assert_eq!(describe(&parent.as_node()), ["x", "c", "b", "a"]);
+
+        b.replace_with(["y".into(), NodeOrString::from(Dom::clone(&b)), "z".into()]).unwrap();
+        assert_eq!(describe(&parent.as_node()), ["x", "c", "y", "b", "z", "a"]);
+
+        c.replace_with(["w"]).unwrap();
+        assert_eq!(describe(&parent.as_node()), ["x", "w", "y", "b", "z", "a"]);
+        assert!(c.borrow().parent().is_none());
+
+        a.remove();
+        assert_eq!(describe(&parent.as_node()), ["x", "w", "y", "b", "z"]);
+
+        // Without a parent, nothing happens.
+        a.before(["v"]).unwrap();
+        assert!(a.borrow().previous_sibling().is_none());
+    }
+
+    #[test]
+    fn strings_join_the_document() {
+        hierarchy_init();
+
+        let document = Document::create();
+        let element = document.borrow().create_element(String::from("div"));
+        element.append(["text"]).unwrap();
+
+        let text = element.borrow().first_child().unwrap();
+        assert!(text.is::<Text>());
+        assert!(text.borrow().node_id().is_some());
+    }
+
+    #[test]
+    fn invalid_trees_are_rejected() {
+        hierarchy_init();
+
+        let document = Document::create();
+        let error = Err(DomException::HierarchyRequestError("A document can't have text children"));
+        assert_eq!(document.append(["text"]), error);
+
+        let element = document.borrow().create_element(String::from("div"));
+        document.append([Dom::clone(&element)]).unwrap();
+        assert_eq!(document.append([document.borrow().create_element(String::from("div"))]).unwrap_err().name(), "HierarchyRequestError");
+        assert_eq!(document.child_element_count(), 1);
+
+        // The element can be replaced by another though.
+        let other = document.borrow().create_element(String::from("div"));
+        element.replace_with([Dom::clone(&other)]).unwrap();
+        assert!(document.first_element_child().unwrap() == other);
+
+        let child = Element::create(String::from("div"));
+        other.append([Dom::clone(&child)]).unwrap();
+        assert!(child.append([Dom::clone(&other)]).is_err());
+        assert!(child.append([Dom::clone(&document)]).is_err());
+        assert!(child.append([Dom::clone(&child)]).is_err());
+    }
+
+    #[test]
+    fn fragment_is_emptied_into_parent() {
+        hierarchy_init();
+
+        let fragment = DocumentFragment::create();
+        fragment.append(["a", "b"]).unwrap();
+
+        let parent = Element::create(String::from("div"));
+        parent.append(["c".into(), NodeOrString::from(Dom::clone(&fragment))]).unwrap();
+
+        assert_eq!(describe(&parent.as_node()), ["c", "a", "b"]);
+        assert!(fragment.borrow().first_child().is_none());
+        assert_eq!(fragment.borrow().node_name(), "#document-fragment");
+    }
+}
@@ -1,28 +1,67 @@
 mod node;
 mod document;
+mod document_fragment;
 mod element;
+mod character_data;
+mod text;
+mod node_id;
+mod mixins;
+mod dom_exception;
 
-pub use node::Node;
-pub use document::Document;
+pub use node::{Node, NodeMethods};
+pub use document::{Document, NodeDocument};
+pub use document_fragment::DocumentFragment;
 pub use element::Element;
+pub use character_data::CharacterData;
+pub use text::Text;
+pub use node_id::NodeId;
+pub use mixins::{AsNode, ParentNode, ChildNode, NonDocumentTypeChildNode, NodeOrString};
+pub use dom_exception::DomException;
 
 // An interface is represented by a struct which has the methods of the
 // interface implemented on it. Each interface must be uniquely identified by
 // an ID. Inheritance is accomplished through struct composition and casting is
 // attained by keeping track of the inheritance hierarchy of all interfaces and
-// storing the ID of the top-most interface in the base interface.
+// storing the ID of the top-most interface in the header of its `Dom`.
 //
 // When creating an interface, let's call if `Foo`, you must follow these steps:
 // 1.  Create the struct `Foo`, optionally containing relevant fields.
-// 2a. If the interface `Foo` inherits from another interface `Bar`, then the
+// 2.  If the interface `Foo` inherits from another interface `Bar`, then the
 //     FIRST field in the struct `Foo` MUST be a `Bar` struct. To make the
 //     purpose of the field clear, it SHOULD be named `_inherited`.
-// 2b. If the interface `Foo` does not inherit from another interface, i.e. it
-//     is a base interface, then the FIRST field of the struct `Foo` MUST be a
-//     `InterfaceID`. This ID will be set to the ID of the top-most interface.
-//     To make the purpose of the field clear, it SHOULD be named `_top'.
 // 3.  Mark the struct `Foo` as `#[repr(C)]` to make sure that the field
-//     created in 2a or 2b is actually stored as the first field in memory.
+//     created in 2 is actually stored as the first field in memory.
 // 4.  Implement the `id` function of the `Interface` trait for `Foo`. The ID
-//     that it returns MUST be unique among all implemented interfaces.
-// 5.  Add a hierarchy registration call in the `init` method in the crate root.
+//     that it returns MUST be unique among all implemented interfaces, which
+//     `InterfaceID::from_name` takes care of.
+// 5.  Register `Foo` in the hierarchy with `Interface::register`, after the
+//     interface it inherits from and before `init` in the crate root freezes
+//     the hierarchy.
+// 6.  Create the values of `Foo` with `Interface::into_dom`, which records
+//     `Foo` as their top-most interface and runs `Interface::created`, e.g.
+//     so that a node can hand out handles to itself.
+// 7.  Implement `Inherits` for `Foo` for each interface that `Bar` inherits
+//     from, so that it can be upcast to them with `Upcast`, i.e.
+//     `unsafe impl<A: Interface> Inherits<A> for Foo where Bar: Inherits<A>`.
+// 8.  If `Foo` behaves differently from the interface it inherits from, e.g.
+//     has a node name of its own, give it `NodeMethods` of its own, which are
+//     registered along with it in step 5.
+//
+// `#[derive(Interface)]` takes care of steps 4 and 7, of registering the
+// methods of step 8 and of running the `created` hook of the inherited
+// interface in step 6, along with `Deref` and `DerefMut` to the inherited
+// interface, and checks that steps 2 and 3 were followed, e.g. for an
+// interface that inherits from `Element`:
+// ```
+// #[derive(Interface)]
+// #[interface(extends(Element), methods = Foo::METHODS)]
+// #[repr(C)]
+// struct Foo {
+//     _inherited: Element,
+// }
+// ```
+//
+// The mixins of the DOM standard, e.g. `ParentNode`, are traits that are
+// implemented on the handles of the interfaces that include them, see
+// `mixins.rs`. An interface that inherits from one of those interfaces MUST
+// implement the mixins for its own handle as well.
@@ -1,26 +1,163 @@
-use crate::Dom;
-use crate::{Interface, InterfaceID};
-
+use crate::{Dom, WeakDom, Registry};
+use crate::Interface;
+use crate::{Cast, Inherits, Upcast};
+use crate::{Trace, Tracer};
+use crate::{MallocSizeOf, MemoryReport};
+use crate::interface::NodeId;
+use crate::interface::document::NodeDocument;
+use crate::cast::methods_of;
+
+// The tree is owned from the top down: a node owns its first child and its
+// next sibling, so each node is owned by exactly one other node.
+// The remaining links point back up (or to the left) in the tree and are weak,
+// since they would otherwise form reference cycles that are never freed.
+//
+// `this` points to the node itself, so that the methods on a node can hand out
+// handles to it when it is inserted into a tree, see `Node::handle`.
+//
+// `id` is the ID of the node in its document, i.e. the document that created
+// it or that it has since been inserted into, along with what the node knows
+// of that document, see `NodeId` and `NodeDocument`.
+#[derive(Interface)]
+#[interface(crate = crate, created = Node::adopt, methods = NodeMethods::DEFAULT)]
 #[repr(C)]
 pub struct Node {
-    _top: InterfaceID,
-    parent: Option<Dom<Node>>,
+    this: Option<WeakDom<Node>>,
+    id: Option<(NodeId, NodeDocument)>,
+    parent: Option<WeakDom<Node>>,
     first_child: Option<Dom<Node>>,
-    last_child: Option<Dom<Node>>,
-    previous_sibling: Option<Dom<Node>>,
+    last_child: Option<WeakDom<Node>>,
+    previous_sibling: Option<WeakDom<Node>>,
     next_sibling: Option<Dom<Node>>,
 }
 
-impl Interface for Node {
-    fn id() -> InterfaceID {
-        InterfaceID::new(1)
+// Only the owning links are traced, the others are weak.
+unsafe impl Trace for Node {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.first_child.trace(tracer);
+        self.next_sibling.trace(tracer);
+    }
+}
+
+// The links to other nodes don't own any memory of their own, so a node only
+// reports the nodes in its subtree.
+// NOTE The descendants are only known as `Node`s, so they are reported by
+//      the `NodeMethods` of their top-most interface, which includes the
+//      memory that is owned by the interfaces that inherit from `Node`.
+impl MallocSizeOf for Node {
+    fn shallow_size_of(&self, _report: &mut MemoryReport) {}
+
+    fn deep_size_of(&self, report: &mut MemoryReport) {
+        self.shallow_size_of(report);
+
+        // The subtree is walked iteratively, for the same reason as it is
+        // dismantled iteratively in `drop`.
+        let mut stack: Vec<Dom<Node>> = self.first_child().into_iter().collect();
+
+        while let Some(node) = stack.pop() {
+            let methods = node.borrow().methods();
+            (methods.shallow_size_of)(&node, report);
+            stack.extend(node.borrow().next_sibling());
+            stack.extend(node.borrow().first_child());
+        }
+    }
+}
+
+// Dropping a node drops the nodes it owns, which would recurse once for each
+// level of the tree and for each sibling, and overflow the stack for a deep or
+// wide enough tree. The subtree is instead dismantled here, by moving the
+// owning links of each node that is about to be dropped onto a stack.
+impl Drop for Node {
+    fn drop(&mut self) {
+        if let Some((id, document)) = self.id.take() {
+            document.remove(id);
+        }
+
+        let mut stack: Vec<Dom<Node>> = Vec::new();
+        stack.extend(self.first_child.take());
+        stack.extend(self.next_sibling.take());
+
+        while let Some(node) = stack.pop() {
+            // The garbage that the cycle collector found is dropped by it, so
+            // the nodes of it are left alone, as some of them might already
+            // have been dropped.
+            if Dom::is_dead(&node) {
+                continue;
+            }
+
+            let kept = Dom::strong_count(&node) > 1;
+
+            if let Ok(mut node) = node.try_borrow_mut() {
+                // A node that is referenced from elsewhere is kept, along with
+                // its children, but it is taken out of the tree, since its
+                // siblings are dropped with the rest of it.
+                if kept {
+                    node.parent = None;
+                    node.previous_sibling = None;
+                } else {
+                    stack.extend(node.first_child.take());
+                }
+
+                stack.extend(node.next_sibling.take());
+            }
+
+            // The node doesn't own any other nodes by now, so dropping it
+            // doesn't recurse.
+        }
+    }
+}
+
+// The behaviour of a node that differs by the interface it was created as,
+// e.g. the steps that the DOM standard leaves to the interfaces that inherit
+// from `Node`. The methods are dispatched on the top-most interface of the
+// node, so an interface registers a table of its own with
+// `Hierarchy::register_methods`, or else inherits the one of its base.
+//
+// The methods are given the handle of the node, which they can cast to the
+// interface that registered them, according to `Node::registry`.
+// NOTE The nodes of a document that was created against a registry of its
+//      own are dispatched according to that registry, see `Node::methods`.
+pub struct NodeMethods {
+    // The `nodeName` of the node.
+    pub node_name: fn(&Dom<Node>) -> String,
+    // Creates a copy of the node without its children, in the document if
+    // any, see `clone_node`. A copy that isn't created by the document joins
+    // it afterwards, but is allocated elsewhere.
+    pub clone: fn(&Dom<Node>, Option<&NodeDocument>) -> Dom<Node>,
+    // Run on the copy right after it has been created, with the node that it
+    // is a copy of.
+    pub cloning_steps: fn(&Dom<Node>, &Dom<Node>),
+    // Adds the shallow size of the node to the report, see `MallocSizeOf`.
+    pub shallow_size_of: fn(&Dom<Node>, &mut MemoryReport),
+    // Run on the node, and on each of its descendants in tree order, right
+    // after it has been inserted into a tree by `Node::insert` or by the
+    // mixins, e.g. `ParentNode::append`.
+    pub insertion_steps: fn(&Dom<Node>),
+}
+
+impl NodeMethods {
+    // The methods of `Node` itself, which the interfaces that inherit from it
+    // can start from with `..NodeMethods::DEFAULT`.
+    pub const DEFAULT: NodeMethods = NodeMethods {
+        node_name: |_| String::new(),
+        clone: |_, _| Node::create(),
+        cloning_steps: |_, _| {},
+        shallow_size_of: |node, report| node.shallow_size_of(report),
+        insertion_steps: |_| {},
+    };
+}
+
+impl Default for Node {
+    fn default() -> Self {
+        Node::new()
     }
 }
 
 impl Node {
     pub fn new() -> Self {
         Node {
-            _top: unsafe { InterfaceID::zero() },
+            this: None,
+            id: None,
             parent: None,
             first_child: None,
             last_child: None,
@@ -29,8 +166,241 @@ impl Node {
         }
     }
 
+    pub fn create() -> Dom<Self> {
+        Node::new().into_dom()
+    }
+
+    // Lets the node that `dom` points to find its own handle.
+    pub fn adopt<T: Inherits<Node>>(dom: &Dom<T>) {
+        let node: Dom<Node> = Dom::clone(dom).upcast();
+        let this = Dom::downgrade(&node);
+        node.borrow_mut().this = Some(this);
+
+        #[cfg(feature = "leak-check")]
+        crate::leak::set_interface(node.header(), Dom::top_interface(&node));
+    }
+
+    // Gives the node an ID in a document.
+    pub(crate) fn assign_id<T: Inherits<Node>>(dom: &Dom<T>, document: &NodeDocument) {
+        let node: Dom<Node> = Dom::clone(dom).upcast();
+        let id = document.insert(Dom::downgrade(&node));
+
+        let mut node = node.borrow_mut();
+        debug_assert!(node.id.is_none());
+        node.id = Some((id, document.clone()));
+    }
+
+    // Moves `node` and its descendants into the document of this node, if
+    // any, e.g. for a node that is inserted into this one or created on its
+    // behalf. They are given an ID in the document, and give up the one that
+    // they had in another document.
+    // NOTE A node that is inserted into a node without a document keeps the
+    //      document it has.
+    pub(crate) fn share_document(&self, node: &Dom<Node>) {
+        let Some(document) = self.document() else {
+            return;
+        };
+
+        let in_document = |node: &Node| node.document().is_some_and(|other| other.ptr_eq(document));
+
+        // The descendants of a node are in its document as well, so there's
+        // nothing to walk for a node that stays in the same document.
+        if in_document(&node.borrow()) {
+            return;
+        }
+
+        let mut stack = vec![Dom::clone(node)];
+
+        while let Some(node) = stack.pop() {
+            let mut node_ref = node.borrow_mut();
+
+            if in_document(&node_ref) {
+                continue;
+            }
+
+            if let Some((id, other)) = node_ref.id.take() {
+                other.remove(id);
+            }
+
+            let id = document.insert(Dom::downgrade(&node));
+            node_ref.id = Some((id, document.clone()));
+            stack.extend(std::iter::successors(node_ref.first_child(), |child| child.borrow().next_sibling()));
+        }
+    }
+
+    // The document of the node, if any. The nodes that are created on behalf
+    // of this one are created through it.
+    pub(crate) fn document(&self) -> Option<&NodeDocument> {
+        self.id.as_ref().map(|(_, document)| document)
+    }
+
+    // The registry of the document of the node, if it isn't the global one,
+    // which the checks on the nodes of the tree within the crate go by.
+    pub(crate) fn document_registry(&self) -> Option<&Registry> {
+        self.document()?.registry()
+    }
+
+    // The registry that the node is told apart by, i.e. the one of its
+    // document, or else the global one, e.g. to cast the node in its methods
+    // with `Cast::cast_in`, see `NodeMethods`.
+    pub fn registry(&self) -> Option<&Registry> {
+        match self.document_registry() {
+            Some(registry) => Some(registry),
+            None => Registry::global(),
+        }
+    }
+
+    // Casts the node according to the registry of its document, like the
+    // methods of the node are dispatched, see `Node::registry`.
+    pub(crate) fn downcast<U: Interface>(node: &Dom<Node>) -> Dom<U> {
+        let registry = node.borrow().document_registry().cloned();
+
+        match registry {
+            Some(registry) => Dom::clone(node).cast_in(&registry),
+            None => Dom::clone(node).cast(),
+        }
+    }
+
+    // Returns the ID of the node in its document, or `None` if it wasn't
+    // created by a document nor inserted into one.
+    // NOTE Not named `id`, since `Node::id` is the ID of the interface.
+    pub fn node_id(&self) -> Option<NodeId> {
+        self.id.as_ref().map(|(id, _)| *id)
+    }
+
+    // The methods of the top-most interface of the node, according to the
+    // registry of its document, if any. `Node` has its own, in case the
+    // hierarchy hasn't been initialized.
+    fn methods(&self) -> &'static NodeMethods {
+        let top = Dom::top_interface(&self.handle());
+
+        let methods = match self.document_registry() {
+            Some(registry) => registry.methods(top),
+            None => methods_of(top),
+        };
+
+        methods.unwrap_or(&NodeMethods::DEFAULT)
+    }
+
+    pub fn node_name(&self) -> String {
+        let node = self.handle();
+        (self.methods().node_name)(&node)
+    }
+
+    // Returns a copy of the node, along with copies of its descendants if
+    // `deep` is set. The copies aren't inserted into a tree, but a copy of a
+    // node that is in a document gets an ID in that document.
+    // NOTE The copies of the descendants are created in the document of the
+    //      copy, which is the copy itself for a document.
+    pub fn clone_node(&self, deep: bool) -> Dom<Node> {
+        let copy = self.clone_single(self.document());
+
+        if deep {
+            let document = copy.borrow().document().cloned();
+
+            // The subtree is copied iteratively, for the same reason as it is
+            // dismantled iteratively in `drop`.
+            let mut stack = vec![(self.first_child(), Dom::clone(&copy))];
+
+            while let Some((child, parent)) = stack.pop() {
+                let Some(child) = child else {
+                    continue;
+                };
+
+                let child_copy = child.borrow().clone_single(document.as_ref());
+                parent.borrow_mut().append(Dom::clone(&child_copy));
+
+                stack.push((child.borrow().next_sibling(), parent));
+                stack.push((child.borrow().first_child(), child_copy));
+            }
+        }
+
+        copy
+    }
+
+    fn clone_single(&self, document: Option<&NodeDocument>) -> Dom<Node> {
+        let node = self.handle();
+        let methods = self.methods();
+        let copy = (methods.clone)(&node, document);
+
+        // NOTE A document gives its copy an ID of its own, and the copies of
+        //      the other nodes are created by the document, but a copy that
+        //      is created otherwise, e.g. by `NodeMethods::DEFAULT`, joins it
+        //      here.
+        if let Some(document) = document.filter(|_| copy.borrow().id.is_none()) {
+            Node::assign_id(&copy, document);
+        }
+
+        (methods.cloning_steps)(&copy, &node);
+        copy
+    }
+
+    // Inserts `node` into `parent` before `child`, or as the last child, and
+    // takes it out of the tree that it was in, if any. The insertion steps
+    // are run afterwards, see `Node::inserted`.
+    // NOTE This and the mixins, e.g. `ParentNode::append`, are how nodes are
+    //      inserted, since the tree operations of `Node` itself only change
+    //      the links. Unlike the mixins, it doesn't check that the tree stays
+    //      valid though.
+    pub fn insert(node: Dom<Node>, parent: &Dom<Node>, child: Option<&Dom<Node>>) {
+        node.borrow_mut().detach();
+
+        match child {
+            Some(child) => {
+                debug_assert!(child.borrow().parent().as_ref() == Some(parent));
+                child.borrow_mut().insert_before(Dom::clone(&node));
+            },
+            None => parent.borrow_mut().append(Dom::clone(&node)),
+        }
+
+        Node::inserted(&node);
+    }
+
+    // Runs the insertion steps of `node` and of its descendants, in tree
+    // order, after it has been inserted, once none of the nodes of the tree
+    // are borrowed anymore.
+    // NOTE The nodes are gathered first, since the steps might change the
+    //      tree.
+    pub(crate) fn inserted(node: &Dom<Node>) {
+        let mut nodes = Vec::new();
+        let mut stack = vec![Dom::clone(node)];
+
+        while let Some(node) = stack.pop() {
+            let last_child = node.borrow().last_child();
+            stack.extend(std::iter::successors(last_child, |child| child.borrow().previous_sibling()));
+            nodes.push(node);
+        }
+
+        for node in &nodes {
+            let methods = node.borrow().methods();
+            (methods.insertion_steps)(node);
+        }
+    }
+
+    // Returns the root of the tree that the node is in, i.e. the node itself
+    // if it doesn't have a parent.
+    pub fn root(&self) -> Dom<Node> {
+        std::iter::successors(Some(self.handle()), |node| node.borrow().parent())
+            .last()
+            .unwrap()
+    }
+
+    // Returns a handle to the node itself.
+    // Panics if the node isn't owned by a `Dom`, which is the case if it
+    // wasn't created with `create` (or the `create` of the interface that
+    // inherits from `Node`), or if it has been moved out of its `Dom`.
+    pub fn handle(&self) -> Dom<Node> {
+        let handle = self.this.as_ref()
+            .and_then(WeakDom::upgrade)
+            .expect("The node isn't owned by a Dom");
+
+        assert!(handle == *self, "The node has been moved out of its Dom");
+
+        handle
+    }
+
     pub fn parent(&self) -> Option<Dom<Node>> {
-        self.parent.clone()
+        self.parent.as_ref().and_then(WeakDom::upgrade)
     }
 
     pub fn first_child(&self) -> Option<Dom<Node>> {
@@ -38,11 +408,11 @@ impl Node {
     }
 
     pub fn last_child(&self) -> Option<Dom<Node>> {
-        self.last_child.clone()
+        self.last_child.as_ref().and_then(WeakDom::upgrade)
     }
 
     pub fn previous_sibling(&self) -> Option<Dom<Node>> {
-        self.previous_sibling.clone()
+        self.previous_sibling.as_ref().and_then(WeakDom::upgrade)
     }
 
     pub fn next_sibling(&self) -> Option<Dom<Node>> {
@@ -58,31 +428,25 @@ impl Node {
                 debug_assert!(self.previous_sibling().is_none());
                 debug_assert!(self.next_sibling().is_none());
             },
-            Some(mut parent) => {
-                debug_assert!(parent.first_child().is_some());
-                debug_assert!(parent.last_child().is_some());
-                if parent.first_child().unwrap() == *self {
-                    parent.first_child = self.next_sibling();
+            Some(parent) => {
+                debug_assert!(parent.borrow().first_child().is_some());
+                debug_assert!(parent.borrow().last_child().is_some());
+                if parent.borrow().first_child().unwrap() == *self {
+                    parent.borrow_mut().first_child = self.next_sibling();
                 }
 
-                if parent.last_child().unwrap() == *self {
-                    parent.last_child = self.previous_sibling();
+                if parent.borrow().last_child().unwrap() == *self {
+                    parent.borrow_mut().last_child = self.previous_sibling.clone();
                 }
 
                 // TODO The `None` case in these two matches should basically
                 // correspond to the if-cases above.
-                match self.previous_sibling() {
-                    Some(mut prev) => {
-                        prev.next_sibling = self.next_sibling();
-                    },
-                    None => (),
+                if let Some(prev) = self.previous_sibling() {
+                    prev.borrow_mut().next_sibling = self.next_sibling();
                 }
 
-                match self.next_sibling() {
-                    Some(mut next) => {
-                        next.previous_sibling = self.previous_sibling();
-                    },
-                    None => (),
+                if let Some(next) = self.next_sibling() {
+                    next.borrow_mut().previous_sibling = self.previous_sibling.clone();
                 }
 
                 self.previous_sibling = None;
@@ -92,201 +456,533 @@ impl Node {
         }
     }
 
+    // NOTE The tree operations below only change the links, and are called
+    //      with a node of the tree borrowed, so they don't run the insertion
+    //      steps, see `Node::insert`. Not all of them are used by the crate
+    //      itself yet.
+
     // Append `node` as the last child of `self`.
-    pub fn append(&mut self, mut node: Dom<Node>) {
-        debug_assert!(node.parent().is_none());
-        debug_assert!(node.next_sibling().is_none());
-        debug_assert!(node.previous_sibling().is_none());
+    pub(crate) fn append(&mut self, node: Dom<Node>) {
+        debug_assert!(node.borrow().parent().is_none());
+        debug_assert!(node.borrow().next_sibling().is_none());
+        debug_assert!(node.borrow().previous_sibling().is_none());
         debug_assert!(node != *self);
+        self.share_document(&node);
 
-        node.previous_sibling = self.last_child();
-        node.next_sibling = None;
-        node.parent = Some(Dom::from(&*self));
+        {
+            let mut node = node.borrow_mut();
+            node.previous_sibling = self.last_child.clone();
+            node.next_sibling = None;
+            node.parent = Some(Dom::downgrade(&self.handle()));
+        }
 
-        match self.last_child() {
-            Some(mut last) => {
-                last.next_sibling = Some(Dom::clone(&node));
+        // self.last_child is set before the match, since `node` is moved into
+        // the tree by the match.
+        let last = self.last_child();
+        self.last_child = Some(Dom::downgrade(&node));
+
+        match last {
+            Some(last) => {
+                last.borrow_mut().next_sibling = Some(node);
             },
             None => {
                 debug_assert!(self.first_child().is_none());
-                self.first_child = Some(Dom::clone(&node));
-                // self.last_child is set at the end of the match
+                self.first_child = Some(node);
             },
         }
-
-        self.last_child = Some(Dom::clone(&node));
     }
 
     // Prepend `node` as the first child of `self`.
-    pub fn prepend(&mut self, mut node: Dom<Node>) {
-        debug_assert!(node.parent().is_none());
-        debug_assert!(node.next_sibling().is_none());
-        debug_assert!(node.previous_sibling().is_none());
+    #[allow(dead_code)]
+    pub(crate) fn prepend(&mut self, node: Dom<Node>) {
+        debug_assert!(node.borrow().parent().is_none());
+        debug_assert!(node.borrow().next_sibling().is_none());
+        debug_assert!(node.borrow().previous_sibling().is_none());
         debug_assert!(node != *self);
+        self.share_document(&node);
 
-        node.previous_sibling = None;
-        node.next_sibling = self.first_child();
-        node.parent = Some(Dom::from(&*self));
+        {
+            let mut node = node.borrow_mut();
+            node.previous_sibling = None;
+            node.next_sibling = self.first_child();
+            node.parent = Some(Dom::downgrade(&self.handle()));
+        }
 
         match self.first_child() {
-            Some(mut first) => {
-                first.previous_sibling = Some(Dom::clone(&node));
+            Some(first) => {
+                first.borrow_mut().previous_sibling = Some(Dom::downgrade(&node));
             },
             None => {
                 debug_assert!(self.last_child().is_none());
-                self.last_child = Some(Dom::clone(&node));
+                self.last_child = Some(Dom::downgrade(&node));
                 // self.first_child is set at the end of the match
             },
         }
 
-        self.first_child = Some(Dom::clone(&node));
+        self.first_child = Some(node);
     }
 
     // Insert `node` before `self`.
     // NOTE This is not exactly the same as the `insertBefore` method that is
     //      defined on the `Node` interface in the DOM standard, but the
     //      outcome should be the same.
-    pub fn insert_before(&mut self, mut node: Dom<Node>) {
-        debug_assert!(node.parent().is_none());
-        debug_assert!(node.next_sibling().is_none());
-        debug_assert!(node.previous_sibling().is_none());
+    pub(crate) fn insert_before(&mut self, node: Dom<Node>) {
+        debug_assert!(node.borrow().parent().is_none());
+        debug_assert!(node.borrow().next_sibling().is_none());
+        debug_assert!(node.borrow().previous_sibling().is_none());
         debug_assert!(node != *self);
+        self.parent().unwrap().borrow().share_document(&node);
+
+        {
+            let mut node = node.borrow_mut();
+            node.previous_sibling = self.previous_sibling.clone();
+            node.next_sibling = Some(self.handle());
+            node.parent = self.parent.clone();
+        }
 
-        node.previous_sibling = self.previous_sibling();
-        node.next_sibling = Some(Dom::from(&*self));
-        node.parent = self.parent();
+        // self.previous_sibling is set before the match, since `node` is moved
+        // into the tree by the match.
+        let prev = self.previous_sibling();
+        self.previous_sibling = Some(Dom::downgrade(&node));
 
-        match self.previous_sibling() {
-            Some(mut prev) => {
-                prev.next_sibling = Some(Dom::clone(&node));
+        match prev {
+            Some(prev) => {
+                prev.borrow_mut().next_sibling = Some(node);
             },
             None => {
-                self.parent().unwrap().first_child = Some(Dom::clone(&node));
+                self.parent().unwrap().borrow_mut().first_child = Some(node);
             },
         }
-
-        self.previous_sibling = Some(Dom::clone(&node));
     }
 
     // Insert `node` after `self`.
-    pub fn insert_after(&mut self, mut node: Dom<Node>) {
-        debug_assert!(node.parent().is_none());
-        debug_assert!(node.next_sibling().is_none());
-        debug_assert!(node.previous_sibling().is_none());
+    #[allow(dead_code)]
+    pub(crate) fn insert_after(&mut self, node: Dom<Node>) {
+        debug_assert!(node.borrow().parent().is_none());
+        debug_assert!(node.borrow().next_sibling().is_none());
+        debug_assert!(node.borrow().previous_sibling().is_none());
         debug_assert!(node != *self);
+        self.parent().unwrap().borrow().share_document(&node);
 
-        node.previous_sibling = Some(Dom::from(&*self));
-        node.next_sibling = self.next_sibling();
-        node.parent = self.parent();
+        {
+            let mut node = node.borrow_mut();
+            node.previous_sibling = Some(Dom::downgrade(&self.handle()));
+            node.next_sibling = self.next_sibling();
+            node.parent = self.parent.clone();
+        }
 
         match self.next_sibling() {
-            Some(mut next) => {
-                next.previous_sibling = Some(Dom::clone(&node));
+            Some(next) => {
+                next.borrow_mut().previous_sibling = Some(Dom::downgrade(&node));
             },
             None => {
-                self.parent().unwrap().last_child = Some(Dom::clone(&node));
+                self.parent().unwrap().borrow_mut().last_child = Some(Dom::downgrade(&node));
             },
         }
 
-        self.next_sibling = Some(Dom::clone(&node));
+        self.next_sibling = Some(node);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::Hierarchy;
+    use crate::interface::{AsNode, ChildNode, Document, Element, NodeOrString, ParentNode};
+
+    #[derive(Interface)]
+    #[interface(crate = crate, extends(Node), methods = Custom::METHODS)]
+    #[repr(C)]
+    struct Custom {
+        _inherited: Node,
+    }
+
+    unsafe impl Trace for Custom {
+        fn trace(&self, tracer: &mut Tracer) {
+            self._inherited.trace(tracer);
+        }
+    }
+
+    impl Custom {
+        const METHODS: NodeMethods = NodeMethods {
+            node_name: |_| String::from("custom"),
+            ..NodeMethods::DEFAULT
+        };
+    }
+
+    #[test]
+    fn derived_interface_is_created_and_registered() {
+        let mut hier = Hierarchy::new();
+        Node::register(&mut hier).unwrap();
+        Custom::register(&mut hier).unwrap();
+        let registry = hier.build().unwrap();
+
+        // The node is adopted by the hook of `Node`, without `Custom` having
+        // to know about it.
+        let node: Dom<Node> = Custom { _inherited: Node::new() }.into_dom().upcast();
+        assert!(node.borrow().handle() == node);
+        assert!(node.is_in::<Custom>(&registry));
+
+        let methods = registry.methods::<NodeMethods>(Custom::id()).unwrap();
+        assert_eq!((methods.node_name)(&node), "custom");
+    }
+
+    // An element that keeps track of the steps that were run on it.
+    #[derive(Interface)]
+    #[interface(crate = crate, extends(Element), methods = Steps::METHODS)]
+    #[repr(C)]
+    struct Steps {
+        _inherited: Element,
+        inserted: usize,
+        copy_of: Option<Dom<Node>>,
+    }
+
+    unsafe impl Trace for Steps {
+        fn trace(&self, tracer: &mut Tracer) {
+            self._inherited.trace(tracer);
+            self.copy_of.trace(tracer);
+        }
+    }
+
+    impl Steps {
+        fn new() -> Self {
+            Steps {
+                _inherited: Element::new(String::from("steps")),
+                inserted: 0,
+                copy_of: None,
+            }
+        }
+
+        // Casts the node by the registry of its document, since the global
+        // one doesn't know of `Steps`.
+        fn cast(node: &Dom<Node>) -> Dom<Steps> {
+            let registry = node.borrow().registry().cloned().unwrap();
+            Dom::clone(node).cast_in(&registry)
+        }
+
+        const METHODS: NodeMethods = NodeMethods {
+            clone: |_, document| match document {
+                Some(document) => document.create_node(Steps::new()).upcast(),
+                None => Steps::new().into_dom().upcast(),
+            },
+            cloning_steps: |copy, node| {
+                let copy = Steps::cast(copy);
+                copy.borrow_mut().copy_of = Some(Dom::clone(node));
+            },
+            insertion_steps: |node| {
+                // The parent isn't borrowed anymore by the time the steps run.
+                let parent = node.borrow().parent().unwrap();
+                drop(parent.borrow_mut());
+
+                let node = Steps::cast(node);
+                node.borrow_mut().inserted += 1;
+            },
+            ..Element::METHODS
+        };
+    }
+
+    // A document that knows of `Steps`, along with an element and a `Steps`
+    // node of it.
+    // NOTE The global registry doesn't know of `Steps`, so the node has to be
+    //      in the document before it is told apart.
+    fn steps_document() -> (Dom<Document>, Dom<Element>, Dom<Steps>) {
+        let mut hier = Hierarchy::new();
+        crate::register_interfaces(&mut hier).unwrap();
+        Steps::register(&mut hier).unwrap();
+
+        let document = Document::create_with_registry(hier.build().unwrap());
+        let parent = document.borrow().create_element(String::from("div"));
+        let steps = Steps::new().into_dom_in(document.borrow().arena());
+        parent.borrow().share_document(&steps.as_node());
+
+        (document, parent, steps)
+    }
+
+    #[test]
+    fn insertion_steps_are_run_on_insert() {
+        let (document, parent, steps) = steps_document();
+
+        document.append([Dom::clone(&parent)]).unwrap();
+        parent.append([Dom::clone(&steps)]).unwrap();
+        assert_eq!(steps.borrow().inserted, 1);
+
+        // Once into the fragment that the nodes are gathered in, and once into
+        // the parent.
+        parent.append(["text".into(), NodeOrString::from(Dom::clone(&steps))]).unwrap();
+        assert_eq!(steps.borrow().inserted, 3);
+
+        // The steps are run for the descendants of the inserted node as well.
+        parent.remove();
+        document.append([Dom::clone(&parent)]).unwrap();
+        assert_eq!(steps.borrow().inserted, 4);
+
+        Node::insert(steps.as_node(), &parent.as_node(), None);
+        assert_eq!(steps.borrow().inserted, 5);
+    }
+
+    #[test]
+    fn cloning_steps_are_run_on_copies() {
+        let (_document, parent, steps) = steps_document();
+        parent.append([Dom::clone(&steps)]).unwrap();
+
+        let copy = parent.borrow().clone_node(true);
+        let child = Steps::cast(&copy.borrow().first_child().unwrap());
+
+        assert!(child != steps);
+        assert!(*child.borrow().copy_of.as_ref().unwrap() == steps.as_node());
+        assert_eq!(child.borrow().node_name(), "STEPS");
+        assert_eq!(child.borrow().inserted, 0);
+    }
+
+    #[test]
+    fn clone_deep_tree() {
+        let root = Node::create();
+        let mut parent = Dom::clone(&root);
+
+        // Deep enough to overflow the stack if the copy recursed.
+        for _ in 0..100_000 {
+            let child = Node::create();
+            parent.borrow_mut().append(Dom::clone(&child));
+            parent = child;
+        }
+
+        let copy = root.borrow().clone_node(true);
+        let mut depth = 0;
+        let mut curr = copy.borrow().first_child();
+
+        while let Some(node) = curr {
+            depth += 1;
+            curr = node.borrow().first_child();
+        }
+
+        assert_eq!(depth, 100_000);
+        assert_eq!(copy.borrow().node_name(), "");
+    }
 
     #[test]
     fn detach_new_node() {
-        let mut node = Dom::new(Node::new());
+        let node = Node::create();
 
-        node.detach();
+        node.borrow_mut().detach();
 
-        assert!(node.parent().is_none());
-        assert!(node.previous_sibling().is_none());
-        assert!(node.next_sibling().is_none());
-        assert!(node.first_child().is_none());
-        assert!(node.last_child().is_none());
+        assert!(node.borrow().parent().is_none());
+        assert!(node.borrow().previous_sibling().is_none());
+        assert!(node.borrow().next_sibling().is_none());
+        assert!(node.borrow().first_child().is_none());
+        assert!(node.borrow().last_child().is_none());
     }
 
     #[test]
     fn detach_node_without_siblings() {
-        let mut parent = Dom::new(Node::new());
-        let mut child = Dom::new(Node::new());
+        let parent = Node::create();
+        let child = Node::create();
 
-        parent.append(Dom::clone(&child));
+        parent.borrow_mut().append(Dom::clone(&child));
 
-        child.detach();
+        child.borrow_mut().detach();
 
-        assert!(child.parent().is_none());
-        assert!(child.previous_sibling().is_none());
-        assert!(child.next_sibling().is_none());
-        assert!(child.first_child().is_none());
-        assert!(child.last_child().is_none());
+        assert!(child.borrow().parent().is_none());
+        assert!(child.borrow().previous_sibling().is_none());
+        assert!(child.borrow().next_sibling().is_none());
+        assert!(child.borrow().first_child().is_none());
+        assert!(child.borrow().last_child().is_none());
 
-        assert!(parent.first_child().is_none());
-        assert!(parent.last_child().is_none());
+        assert!(parent.borrow().first_child().is_none());
+        assert!(parent.borrow().last_child().is_none());
     }
 
     #[test]
     fn detach_node_with_siblings() {
-        let mut parent = Dom::new(Node::new());
-        let     first  = Dom::new(Node::new());
-        let     last   = Dom::new(Node::new());
-        let mut node   = Dom::new(Node::new());
+        let parent = Node::create();
+        let first  = Node::create();
+        let last   = Node::create();
+        let node   = Node::create();
+
+        parent.borrow_mut().append(Dom::clone(&first));
+        parent.borrow_mut().append(Dom::clone(&node));
+        parent.borrow_mut().append(Dom::clone(&last));
+
+        node.borrow_mut().detach();
+
+        assert!(node.borrow().parent().is_none());
+        assert!(node.borrow().previous_sibling().is_none());
+        assert!(node.borrow().next_sibling().is_none());
+        assert!(node.borrow().first_child().is_none());
+        assert!(node.borrow().last_child().is_none());
+
+        assert!(first.borrow().parent().is_some());
+        assert!(last.borrow().parent().is_some());
+        assert!(first.borrow().parent().unwrap() == parent);
+        assert!(last.borrow().parent().unwrap() == parent);
+
+        assert!(first.borrow().next_sibling().is_some());
+        assert!(last.borrow().previous_sibling().is_some());
+        assert!(first.borrow().next_sibling().unwrap() == last);
+        assert!(last.borrow().previous_sibling().unwrap() == first);
+
+        assert!(parent.borrow().first_child().is_some());
+        assert!(parent.borrow().last_child().is_some());
+        assert!(parent.borrow().first_child().unwrap() == first);
+        assert!(parent.borrow().last_child().unwrap() == last);
+    }
 
-        parent.append(Dom::clone(&first));
-        parent.append(Dom::clone(&node));
-        parent.append(Dom::clone(&last));
+    #[test]
+    fn detach_node_with_next_sibling() {
+        let parent = Node::create();
+        let node   = Node::create();
+        let next   = Node::create();
 
-        node.detach();
+        parent.borrow_mut().append(Dom::clone(&node));
+        parent.borrow_mut().append(Dom::clone(&next));
 
-        assert!(node.parent().is_none());
-        assert!(node.previous_sibling().is_none());
-        assert!(node.next_sibling().is_none());
-        assert!(node.first_child().is_none());
-        assert!(node.last_child().is_none());
+        node.borrow_mut().detach();
 
-        assert!(first.parent().is_some());
-        assert!(last.parent().is_some());
-        assert!(first.parent().unwrap() == parent);
-        assert!(last.parent().unwrap() == parent);
+        assert!(node.borrow().parent().is_none());
+        assert!(node.borrow().previous_sibling().is_none());
+        assert!(node.borrow().next_sibling().is_none());
+        assert!(node.borrow().first_child().is_none());
+        assert!(node.borrow().last_child().is_none());
 
-        assert!(first.next_sibling().is_some());
-        assert!(last.previous_sibling().is_some());
-        assert!(first.next_sibling().unwrap() == last);
-        assert!(last.previous_sibling().unwrap() == first);
+        assert!(parent.borrow().first_child().is_some());
+        assert!(parent.borrow().last_child().is_some());
+        assert!(parent.borrow().first_child().unwrap() == parent.borrow().last_child().unwrap());
 
-        assert!(parent.first_child().is_some());
-        assert!(parent.last_child().is_some());
-        assert!(parent.first_child().unwrap() == first);
-        assert!(parent.last_child().unwrap() == last);
+        assert!(next.borrow().parent().is_some());
+        assert!(next.borrow().parent().unwrap() == parent);
+        assert!(next.borrow().next_sibling().is_none());
+        assert!(next.borrow().previous_sibling().is_none());
     }
 
     #[test]
-    fn detach_node_with_next_sibling() {
-        let mut parent = Dom::new(Node::new());
-        let mut node   = Dom::new(Node::new());
-        let     next   = Dom::new(Node::new());
+    fn children_are_dropped_with_parent() {
+        let parent = Node::create();
+        let first = Node::create();
+        let last = Node::create();
+
+        let weak_first = Dom::downgrade(&first);
+        let weak_last = Dom::downgrade(&last);
+
+        parent.borrow_mut().append(first);
+        parent.borrow_mut().append(last);
+
+        assert!(weak_first.upgrade().unwrap().borrow().parent().unwrap() == parent);
+        assert!(weak_last.upgrade().unwrap().borrow().previous_sibling().unwrap() == weak_first.upgrade().unwrap());
+
+        drop(parent);
+
+        assert!(weak_first.upgrade().is_none());
+        assert!(weak_last.upgrade().is_none());
+    }
+
+    #[test]
+    fn parent_is_not_kept_alive_by_child() {
+        let parent = Node::create();
+        let child = Node::create();
+
+        let weak_parent = Dom::downgrade(&parent);
+        parent.borrow_mut().append(Dom::clone(&child));
+
+        drop(parent);
+
+        assert!(weak_parent.upgrade().is_none());
+        assert!(child.borrow().parent().is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "isn't owned by a Dom")]
+    fn append_to_node_on_stack() {
+        let mut parent = Node::new();
+
+        parent.append(Node::create());
+    }
+
+    #[test]
+    #[should_panic(expected = "moved out of its Dom")]
+    fn append_to_node_moved_out_of_dom() {
+        let first = Node::create();
+        let second = Node::create();
 
-        parent.append(Dom::clone(&node));
-        parent.append(Dom::clone(&next));
+        std::mem::swap(&mut *first.borrow_mut(), &mut *second.borrow_mut());
 
-        node.detach();
+        first.borrow_mut().append(Node::create());
+    }
+
+    #[test]
+    fn handle_points_to_node() {
+        let node = Node::create();
+
+        assert!(node.borrow().handle() == node);
+        assert_eq!(Dom::strong_count(&node), 1);
+    }
+
+    #[test]
+    fn drop_deep_tree() {
+        let root = Node::create();
+        let mut last = Dom::clone(&root);
+
+        for _ in 0..1_000_000 {
+            let child = Node::create();
+            last.borrow_mut().append(Dom::clone(&child));
+            last = child;
+        }
+
+        let weak_last = Dom::downgrade(&last);
+        drop(last);
+        drop(root);
 
-        assert!(node.parent().is_none());
-        assert!(node.previous_sibling().is_none());
-        assert!(node.next_sibling().is_none());
-        assert!(node.first_child().is_none());
-        assert!(node.last_child().is_none());
+        assert!(weak_last.upgrade().is_none());
+    }
 
-        assert!(parent.first_child().is_some());
-        assert!(parent.last_child().is_some());
-        assert!(parent.first_child().unwrap() == parent.last_child().unwrap());
+    #[test]
+    fn drop_wide_tree() {
+        let root = Node::create();
 
-        assert!(next.parent().is_some());
-        assert!(next.parent().unwrap() == parent);
-        assert!(next.next_sibling().is_none());
-        assert!(next.previous_sibling().is_none());
+        for _ in 0..1_000_000 {
+            root.borrow_mut().append(Node::create());
+        }
+
+        let weak_last = Dom::downgrade(&root.borrow().last_child().unwrap());
+        drop(root);
+
+        assert!(weak_last.upgrade().is_none());
+    }
+
+    #[test]
+    fn drop_keeps_referenced_subtree() {
+        let root = Node::create();
+        let child = Node::create();
+        let grandchild = Node::create();
+
+        child.borrow_mut().append(Dom::clone(&grandchild));
+        root.borrow_mut().append(Dom::clone(&child));
+        drop(grandchild);
+
+        drop(root);
+
+        assert!(child.borrow().parent().is_none());
+        assert!(child.borrow().first_child().is_some());
+    }
+
+    #[test]
+    fn drop_keeps_referenced_node_without_siblings() {
+        let root = Node::create();
+        let first = Node::create();
+        let middle = Node::create();
+        let last = Node::create();
+
+        let weak_first = Dom::downgrade(&first);
+        let weak_last = Dom::downgrade(&last);
+
+        root.borrow_mut().append(first);
+        root.borrow_mut().append(Dom::clone(&middle));
+        root.borrow_mut().append(last);
+
+        drop(root);
+
+        assert!(weak_first.upgrade().is_none());
+        assert!(weak_last.upgrade().is_none());
+        assert!(middle.borrow().parent().is_none());
+        assert!(middle.borrow().previous_sibling().is_none());
+        assert!(middle.borrow().next_sibling().is_none());
     }
 }
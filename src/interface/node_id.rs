@@ -0,0 +1,119 @@
+use crate::interface::Node;
+use crate::sync::{Lock, Shared};
+use crate::{Dom, WeakDom};
+
+// A `Dom<Node>` can't be serialized, sent in a message or handed out over FFI,
+// and holding on to one keeps the node alive. A `NodeId` identifies a node of
+// a document instead, and is looked up with `Document::node`.
+//
+// The IDs index into a slot map that is owned by the document. When a node is
+// dropped its slot is reused, but with a new generation, so that the IDs of
+// the old node don't find the new one.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct NodeId {
+    index: u32,
+    generation: u32,
+}
+
+impl NodeId {
+    // The ID as a single integer, e.g. to serialize it.
+    pub fn to_bits(self) -> u64 {
+        (self.generation as u64) << 32 | self.index as u64
+    }
+
+    pub fn from_bits(bits: u64) -> NodeId {
+        NodeId {
+            index: bits as u32,
+            generation: (bits >> 32) as u32,
+        }
+    }
+}
+
+struct Slot {
+    generation: u32,
+    node: Option<WeakDom<Node>>,
+}
+
+struct Slots {
+    slots: Vec<Slot>,
+    // The indices of the slots that aren't used.
+    free: Vec<u32>,
+}
+
+// The slot map of a document. Each node that has an ID holds on to the map,
+// so that it can free its slot when it is dropped, even if the document is
+// mutably borrowed or already gone by then.
+#[derive(Clone)]
+pub(crate) struct NodeIds {
+    inner: Shared<Lock<Slots>>,
+}
+
+impl NodeIds {
+    pub(crate) fn new() -> Self {
+        NodeIds {
+            inner: Shared::new(Lock::new(Slots {
+                slots: Vec::new(),
+                free: Vec::new(),
+            })),
+        }
+    }
+
+    pub(crate) fn insert(&self, node: WeakDom<Node>) -> NodeId {
+        let mut slots = self.inner.lock();
+
+        match slots.free.pop() {
+            Some(index) => {
+                let slot = &mut slots.slots[index as usize];
+                slot.node = Some(node);
+
+                NodeId {
+                    index,
+                    generation: slot.generation,
+                }
+            },
+            None => {
+                let index = u32::try_from(slots.slots.len()).expect("Too many nodes in the document");
+                slots.slots.push(Slot {
+                    generation: 0,
+                    node: Some(node),
+                });
+
+                NodeId {
+                    index,
+                    generation: 0,
+                }
+            },
+        }
+    }
+
+    // Frees the slot of a node that is dropped.
+    pub(crate) fn remove(&self, id: NodeId) {
+        let mut slots = self.inner.lock();
+        let slot = &mut slots.slots[id.index as usize];
+        debug_assert!(slot.generation == id.generation);
+
+        // The weak handle is dropped after the lock is released.
+        let node = slot.node.take();
+        slot.generation = slot.generation.wrapping_add(1);
+        slots.free.push(id.index);
+        drop(slots);
+
+        drop(node);
+    }
+
+    // Tells if the handles are to the same slot map.
+    pub(crate) fn ptr_eq(&self, other: &NodeIds) -> bool {
+        Shared::ptr_eq(&self.inner, &other.inner)
+    }
+
+    pub(crate) fn get(&self, id: NodeId) -> Option<Dom<Node>> {
+        let slots = self.inner.lock();
+        let slot = slots.slots.get(id.index as usize)?;
+
+        if slot.generation != id.generation {
+            return None;
+        }
+
+        slot.node.as_ref().and_then(WeakDom::upgrade)
+    }
+}
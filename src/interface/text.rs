@@ -0,0 +1,64 @@
+use crate::{Arena, Dom};
+use crate::interface::{Node, NodeMethods, CharacterData};
+use crate::Interface;
+use crate::Upcast;
+use crate::{Trace, Tracer};
+use crate::{MallocSizeOf, MemoryReport};
+
+#[derive(Interface)]
+#[interface(crate = crate, extends(CharacterData), methods = Text::METHODS)]
+#[repr(C)]
+pub struct Text {
+    _inherited: CharacterData,
+}
+
+unsafe impl Trace for Text {
+    fn trace(&self, tracer: &mut Tracer) {
+        self._inherited.trace(tracer);
+    }
+}
+
+impl MallocSizeOf for Text {
+    fn shallow_size_of(&self, report: &mut MemoryReport) {
+        self._inherited.shallow_size_of(report);
+    }
+
+    fn deep_size_of(&self, report: &mut MemoryReport) {
+        self._inherited.deep_size_of(report);
+    }
+}
+
+impl Text {
+    pub fn new(data: String) -> Self {
+        Text {
+            _inherited: CharacterData::new(data),
+        }
+    }
+
+    pub fn create(data: String) -> Dom<Self> {
+        Text::new(data).into_dom()
+    }
+
+    // Creates the text in an arena, e.g. the one of a document.
+    pub fn create_in(arena: &Arena, data: String) -> Dom<Self> {
+        Text::new(data).into_dom_in(arena)
+    }
+
+    pub const METHODS: NodeMethods = NodeMethods {
+        node_name: |_| String::from("#text"),
+        clone: |node, document| {
+            let text: Dom<Text> = Node::downcast(node);
+            let data = text.borrow().data().to_owned();
+
+            match document {
+                Some(document) => document.create_text_node(data).upcast(),
+                None => Text::create(data).upcast(),
+            }
+        },
+        shallow_size_of: |node, report| {
+            let text: Dom<Text> = Node::downcast(node);
+            text.shallow_size_of(report);
+        },
+        ..NodeMethods::DEFAULT
+    };
+}
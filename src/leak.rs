@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::ptr::NonNull;
+use std::sync::{Mutex, MutexGuard};
+use std::thread::{self, ThreadId};
+
+use once_cell::sync::Lazy;
+
+use crate::dom::Header;
+use crate::InterfaceID;
+
+// With the `leak-check` feature every value that is put in a `Dom` is tracked
+// from when it is created until it is dropped, so that a test can tell if it
+// leaked a tree, e.g. through a reference cycle that was never collected.
+//
+// Values that are nodes are also tracked by the interface they were created
+// as. Each value remembers the thread that created it, which lets the tests
+// that run in parallel check their own values only.
+
+struct Entry {
+    thread: ThreadId,
+    interface: Option<InterfaceID>,
+}
+
+// Keyed by the address of the header.
+static LIVE: Lazy<Mutex<HashMap<usize, Entry>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn live() -> MutexGuard<'static, HashMap<usize, Entry>> {
+    LIVE.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+pub(crate) fn track(header: NonNull<Header>) {
+    let entry = Entry {
+        thread: thread::current().id(),
+        interface: None,
+    };
+
+    let old = live().insert(header.as_ptr() as usize, entry);
+    debug_assert!(old.is_none());
+}
+
+pub(crate) fn untrack(header: NonNull<Header>) {
+    let old = live().remove(&(header.as_ptr() as usize));
+    debug_assert!(old.is_some());
+}
+
+// Records the top-most interface of the value.
+pub(crate) fn set_interface(header: NonNull<Header>, interface: InterfaceID) {
+    if let Some(entry) = live().get_mut(&(header.as_ptr() as usize)) {
+        entry.interface = Some(interface);
+    }
+}
+
+// The values that haven't been dropped yet.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LiveAllocations {
+    // All of the values, whether they are nodes or not.
+    pub total: usize,
+    // The nodes, by the interface they were created as.
+    pub by_interface: HashMap<InterfaceID, usize>,
+}
+
+impl LiveAllocations {
+    fn collect<'a>(entries: impl Iterator<Item = &'a Entry>) -> Self {
+        let mut allocations = LiveAllocations::default();
+
+        for entry in entries {
+            allocations.total += 1;
+
+            if let Some(interface) = entry.interface {
+                *allocations.by_interface.entry(interface).or_insert(0) += 1;
+            }
+        }
+
+        allocations
+    }
+
+    // The number of nodes, of any interface.
+    pub fn nodes(&self) -> usize {
+        self.by_interface.values().sum()
+    }
+}
+
+// Returns the values that are alive, no matter which thread created them.
+pub fn live_allocations() -> LiveAllocations {
+    LiveAllocations::collect(live().values())
+}
+
+// Returns the values that were created by the current thread and are alive,
+// possibly on another thread.
+pub fn live_allocations_on_current_thread() -> LiveAllocations {
+    let current = thread::current().id();
+    LiveAllocations::collect(live().values().filter(|entry| entry.thread == current))
+}
+
+// Panics if any value that was created by the current thread is still alive.
+// Meant to be called at the end of a test.
+pub fn assert_no_live_allocations() {
+    let allocations = live_allocations_on_current_thread();
+
+    assert!(
+        allocations.total == 0,
+        "{} values are still alive, {} of which are nodes: {:?}",
+        allocations.total,
+        allocations.nodes(),
+        allocations.by_interface,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests_init::hierarchy_init;
+    use crate::interface::{Document, Element};
+    use crate::{Dom, Interface, Upcast};
+
+    #[test]
+    fn counts_nodes_by_interface() {
+        hierarchy_init();
+
+        let document = Document::create();
+        let plain = Dom::new(1234_u32);
+
+        for _ in 0..3 {
+            let element = document.borrow().create_element(String::from("div"));
+            document.borrow_mut().append(element.upcast());
+        }
+
+        let allocations = live_allocations_on_current_thread();
+        assert_eq!(allocations.total, 5);
+        assert_eq!(allocations.nodes(), 4);
+        assert_eq!(allocations.by_interface[&Document::id()], 1);
+        assert_eq!(allocations.by_interface[&Element::id()], 3);
+
+        drop(document);
+        drop(plain);
+        assert_no_live_allocations();
+    }
+
+    #[test]
+    fn detects_leaked_cycle() {
+        hierarchy_init();
+        // Keeps the tests that collect cycles from seeing this one.
+        let _lock = crate::tests_init::collector_lock();
+
+        let element = Element::create(String::from("div"));
+        let child = Element::create(String::from("div"));
+        element.borrow_mut().append(Dom::clone(&child).upcast());
+
+        // The child now owns the parent, which owns the child.
+        child.borrow_mut().append(Dom::clone(&element).upcast());
+        drop(element);
+        drop(child);
+
+        assert_eq!(live_allocations_on_current_thread().nodes(), 2);
+        assert!(std::panic::catch_unwind(assert_no_live_allocations).is_err());
+
+        assert_eq!(crate::collect_cycles(), 2);
+        assert_no_live_allocations();
+    }
+
+    #[test]
+    fn untracked_after_try_unwrap() {
+        let dom = Dom::new(1234_u32);
+        assert_eq!(live_allocations_on_current_thread().total, 1);
+
+        assert_eq!(Dom::try_unwrap(dom).unwrap(), 1234);
+        assert_no_live_allocations();
+    }
+}
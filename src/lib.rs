@@ -1,32 +1,74 @@
 mod dom;
 mod cast;
+mod trace;
+mod sync;
+mod alloc;
+mod arena;
+mod memory;
+#[cfg(feature = "leak-check")]
+mod leak;
 
-pub use crate::dom::Dom;
+pub use crate::dom::{Dom, WeakDom};
+pub use crate::arena::Arena;
+pub use crate::alloc::{Allocator, AllocError};
+pub use crate::sync::MaybeSync;
+pub use crate::dom::{DomRef, DomRefMut, BorrowError, BorrowMutError};
 
-pub use crate::cast::Cast;
+pub use crate::cast::{Cast, CastError, CastIterator, Contains, FilterCast};
+pub use crate::cast::{Inherits, Upcast};
 pub use crate::cast::{Interface, InterfaceID};
-pub use crate::cast::HIERARCHY;
+pub use dom_derive::Interface;
+pub use crate::cast::{HIERARCHY, Hierarchy, HierarchyError, Registry};
+
+pub use crate::trace::{Trace, Tracer, collect_cycles};
+pub use crate::memory::{MallocSizeOf, MemoryReport};
+
+#[cfg(feature = "leak-check")]
+pub use crate::leak::{LiveAllocations, live_allocations, live_allocations_on_current_thread, assert_no_live_allocations};
 
 pub mod interface;
 
-use crate::interface::{Node, Document, Element};
+use crate::interface::{Node, Document, DocumentFragment, Element, CharacterData, Text};
 
-pub fn init() {
-    let mut hier = HIERARCHY.write().unwrap();
+// Registers the interfaces of this crate, and then freezes the hierarchy so
+// that it can be read without locks. The interfaces of other crates MUST be
+// registered before this is called. Fails if the hierarchy isn't valid, see
+// `Hierarchy::validate`.
+pub fn init() -> Result<(), HierarchyError> {
+    register_interfaces(&mut HIERARCHY.write().unwrap())?;
+    cast::freeze()
+}
 
-    hier.register(Node::id(), None);
-    hier.register(Document::id(), Some(Node::id()));
-    hier.register(Element::id(), Some(Node::id()));
+// Registers the interfaces of this crate, e.g. to a hierarchy that a
+// `Registry` of its own is built from.
+pub fn register_interfaces(hier: &mut Hierarchy) -> Result<(), HierarchyError> {
+    Node::register(hier)?;
+    Document::register(hier)?;
+    DocumentFragment::register(hier)?;
+    Element::register(hier)?;
+    CharacterData::register(hier)?;
+    Text::register(hier)
 }
 
 #[cfg(test)]
 pub(crate) mod tests_init {
     use crate::init;
-    use crate::cast::HIERARCHY;
+    use std::sync::{Mutex, MutexGuard, Once};
 
+    // The tests run in parallel and share the global hierarchy,
+    // so it must only be initialized once.
     pub fn hierarchy_init() {
-        HIERARCHY.write().unwrap().clear();
-        init();
+        static INIT: Once = Once::new();
+
+        INIT.call_once(|| init().unwrap());
+    }
+
+    // With the `sync` feature the candidate roots are shared by all threads,
+    // so a test that collects cycles could collect the garbage of another.
+    // Such tests hold this lock while they run.
+    pub fn collector_lock() -> MutexGuard<'static, ()> {
+        static LOCK: Mutex<()> = Mutex::new(());
+        LOCK.lock().unwrap_or_else(|err| err.into_inner())
     }
 }
 
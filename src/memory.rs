@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+
+use crate::{Dom, InterfaceID};
+
+// Reports how much memory values use, in the spirit of `MallocSizeOf` in
+// Servo. The memory is added up in a `MemoryReport`, broken down by the
+// interface of the values that own it, so that e.g. a cache of documents can
+// be kept within a budget.
+//
+// The shallow size of a value is the memory that the value owns on its own,
+// while the deep size also includes the memory of the values it is made of.
+// For a node, that is its subtree: its children and their descendants, but
+// not its siblings even though it owns its next sibling.
+//
+// NOTE The memory of a value that is owned by a `Dom` is counted by the `Dom`,
+//      not by the value, since it is the `Dom` that knows the size of the
+//      allocation that the value lives in.
+
+pub trait MallocSizeOf {
+    // Adds the memory that is owned by the value to the report, not counting
+    // the memory of the value itself.
+    fn shallow_size_of(&self, report: &mut MemoryReport);
+
+    fn deep_size_of(&self, report: &mut MemoryReport) {
+        self.shallow_size_of(report);
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MemoryReport {
+    // The bytes that are used by the values of each interface, by the ID of
+    // the top-most interface of the values.
+    pub by_interface: HashMap<InterfaceID, usize>,
+    // The bytes that are used by values that aren't interfaces.
+    pub other: usize,
+    // The top-most interface of the `Dom` whose value is being reported, see
+    // `add_owned`.
+    owner: Option<InterfaceID>,
+}
+
+impl MemoryReport {
+    // Returns the shallow size of the value.
+    pub fn shallow<T: MallocSizeOf + ?Sized>(value: &T) -> Self {
+        let mut report = MemoryReport::default();
+        value.shallow_size_of(&mut report);
+        report
+    }
+
+    // Returns the deep size of the value.
+    pub fn deep<T: MallocSizeOf + ?Sized>(value: &T) -> Self {
+        let mut report = MemoryReport::default();
+        value.deep_size_of(&mut report);
+        report
+    }
+
+    pub fn add(&mut self, interface: Option<InterfaceID>, bytes: usize) {
+        match interface {
+            Some(interface) => *self.by_interface.entry(interface).or_insert(0) += bytes,
+            None => self.other += bytes,
+        }
+    }
+
+    // Adds memory that is owned by the value that is being reported, under
+    // the interface of the `Dom` that the value lives in. The value itself
+    // can't tell, since it might be part of an interface that inherits from
+    // it.
+    pub fn add_owned(&mut self, bytes: usize) {
+        self.add(self.owner, bytes);
+    }
+
+    pub fn total(&self) -> usize {
+        self.by_interface.values().sum::<usize>() + self.other
+    }
+}
+
+impl<T: MallocSizeOf> MallocSizeOf for Dom<T> {
+    fn shallow_size_of(&self, report: &mut MemoryReport) {
+        let owner = report.enter(self);
+        self.borrow().shallow_size_of(report);
+        report.owner = owner;
+    }
+
+    fn deep_size_of(&self, report: &mut MemoryReport) {
+        let owner = report.enter(self);
+        self.borrow().deep_size_of(report);
+        report.owner = owner;
+    }
+}
+
+impl MemoryReport {
+    // Adds the allocation of the `Dom`, and makes its interface the owner of
+    // the memory that is added until the previous owner, which is returned,
+    // is restored.
+    fn enter<T>(&mut self, dom: &Dom<T>) -> Option<InterfaceID> {
+        let interface = Dom::known_interface(dom);
+        self.add(interface, Dom::allocation_size(dom));
+        std::mem::replace(&mut self.owner, interface)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests_init::hierarchy_init;
+    use crate::interface::{Document, Element, Node, Text};
+    use crate::{Interface, Upcast};
+
+    struct Plain(u64);
+
+    impl MallocSizeOf for Plain {
+        fn shallow_size_of(&self, _report: &mut MemoryReport) {}
+    }
+
+    #[test]
+    fn plain_value_is_reported_as_other() {
+        let dom = Dom::new(Plain(0));
+        let report = MemoryReport::shallow(&dom);
+
+        assert!(report.by_interface.is_empty());
+        assert_eq!(report.other, Dom::allocation_size(&dom));
+        assert!(report.other > std::mem::size_of::<Plain>());
+        assert_eq!(dom.borrow().0, 0);
+    }
+
+    #[test]
+    fn subtree_by_interface() {
+        hierarchy_init();
+
+        let document = Document::create();
+        let first = document.borrow().create_element(String::from("div"));
+        let second = document.borrow().create_element(String::from("div"));
+        let grandchild = Node::create();
+
+        first.borrow_mut().append(Dom::clone(&grandchild));
+        document.borrow_mut().append(Dom::clone(&first).upcast());
+        document.borrow_mut().append(Dom::clone(&second).upcast());
+
+        let shallow = MemoryReport::shallow(&document);
+        assert_eq!(shallow.total(), Dom::allocation_size(&document));
+        assert_eq!(shallow.by_interface.len(), 1);
+
+        let deep = MemoryReport::deep(&document);
+        assert_eq!(deep.by_interface[&Document::id()], Dom::allocation_size(&document));
+        assert_eq!(deep.by_interface[&Element::id()], 2 * (Dom::allocation_size(&first) + "div".len()));
+        assert_eq!(deep.by_interface[&Node::id()], Dom::allocation_size(&grandchild));
+        assert_eq!(deep.other, 0);
+
+        // The subtree of the first element doesn't include its sibling.
+        let deep = MemoryReport::deep(&first);
+        assert_eq!(deep.total(), Dom::allocation_size(&first) + "div".len() + Dom::allocation_size(&grandchild));
+    }
+
+    #[test]
+    fn subtree_includes_data_of_descendants() {
+        hierarchy_init();
+
+        let element = Element::create(String::from("div"));
+        let text = Text::create("x".repeat(1000));
+        element.borrow_mut().append(Dom::clone(&text).upcast());
+
+        let deep = MemoryReport::deep(&element);
+        assert_eq!(deep.by_interface[&Text::id()], Dom::allocation_size(&text) + 1000);
+    }
+}
@@ -0,0 +1,190 @@
+// The shared state of a `Dom` is kept in these types, which are backed by
+// atomics when the `sync` feature is enabled so that a `Dom<T>` can be sent
+// and shared between threads. Without the feature they are backed by plain
+// cells, which is cheaper when a document never leaves its thread.
+
+#[cfg(not(feature = "sync"))]
+mod imp {
+    use std::cell::{Cell, RefCell, RefMut};
+
+    pub(crate) type Shared<T> = std::rc::Rc<T>;
+
+    pub(crate) struct Counter(Cell<usize>);
+
+    impl Counter {
+        pub(crate) const fn new(value: usize) -> Counter {
+            Counter(Cell::new(value))
+        }
+
+        pub(crate) fn get(&self) -> usize {
+            self.0.get()
+        }
+
+        pub(crate) fn set(&self, value: usize) {
+            self.0.set(value);
+        }
+
+        // Returns the new value.
+        pub(crate) fn increment(&self) -> usize {
+            let value = self.0.get() + 1;
+            self.0.set(value);
+            value
+        }
+
+        // Returns the new value.
+        pub(crate) fn decrement(&self) -> usize {
+            let value = self.0.get() - 1;
+            self.0.set(value);
+            value
+        }
+
+        pub(crate) fn compare_exchange(&self, current: usize, new: usize) -> Result<usize, usize> {
+            let value = self.0.get();
+
+            if value == current {
+                self.0.set(new);
+                Ok(value)
+            } else {
+                Err(value)
+            }
+        }
+
+        // Returns the old value.
+        pub(crate) fn fetch_and(&self, mask: usize) -> usize {
+            let value = self.0.get();
+            self.0.set(value & mask);
+            value
+        }
+    }
+
+    pub(crate) struct Flag(Cell<bool>);
+
+    impl Flag {
+        pub(crate) const fn new(value: bool) -> Flag {
+            Flag(Cell::new(value))
+        }
+
+        pub(crate) fn get(&self) -> bool {
+            self.0.get()
+        }
+
+        pub(crate) fn set(&self, value: bool) {
+            self.0.set(value);
+        }
+
+        // Returns the old value.
+        pub(crate) fn replace(&self, value: bool) -> bool {
+            self.0.replace(value)
+        }
+    }
+
+    // The state behind a `Shared` handle that is changed through it, e.g. the
+    // chunks of an `Arena`.
+    pub(crate) struct Lock<T>(RefCell<T>);
+
+    impl<T> Lock<T> {
+        pub(crate) const fn new(value: T) -> Lock<T> {
+            Lock(RefCell::new(value))
+        }
+
+        // Panics if the state is already locked, which the callers never do.
+        pub(crate) fn lock(&self) -> RefMut<'_, T> {
+            self.0.borrow_mut()
+        }
+    }
+
+    // Every type, since nothing is shared between threads.
+    pub trait MaybeSync {}
+
+    impl<T: ?Sized> MaybeSync for T {}
+}
+
+#[cfg(feature = "sync")]
+mod imp {
+    use std::sync::{Mutex, MutexGuard};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    pub(crate) type Shared<T> = std::sync::Arc<T>;
+
+    pub(crate) struct Counter(AtomicUsize);
+
+    impl Counter {
+        pub(crate) const fn new(value: usize) -> Counter {
+            Counter(AtomicUsize::new(value))
+        }
+
+        pub(crate) fn get(&self) -> usize {
+            self.0.load(Ordering::Acquire)
+        }
+
+        pub(crate) fn set(&self, value: usize) {
+            self.0.store(value, Ordering::Release);
+        }
+
+        // Returns the new value.
+        pub(crate) fn increment(&self) -> usize {
+            self.0.fetch_add(1, Ordering::AcqRel) + 1
+        }
+
+        // Returns the new value.
+        pub(crate) fn decrement(&self) -> usize {
+            self.0.fetch_sub(1, Ordering::AcqRel) - 1
+        }
+
+        pub(crate) fn compare_exchange(&self, current: usize, new: usize) -> Result<usize, usize> {
+            self.0.compare_exchange(current, new, Ordering::AcqRel, Ordering::Acquire)
+        }
+
+        // Returns the old value.
+        pub(crate) fn fetch_and(&self, mask: usize) -> usize {
+            self.0.fetch_and(mask, Ordering::AcqRel)
+        }
+    }
+
+    pub(crate) struct Flag(AtomicBool);
+
+    impl Flag {
+        pub(crate) const fn new(value: bool) -> Flag {
+            Flag(AtomicBool::new(value))
+        }
+
+        pub(crate) fn get(&self) -> bool {
+            self.0.load(Ordering::Acquire)
+        }
+
+        pub(crate) fn set(&self, value: bool) {
+            self.0.store(value, Ordering::Release);
+        }
+
+        // Returns the old value.
+        pub(crate) fn replace(&self, value: bool) -> bool {
+            self.0.swap(value, Ordering::AcqRel)
+        }
+    }
+
+    pub(crate) struct Lock<T>(Mutex<T>);
+
+    impl<T> Lock<T> {
+        pub(crate) const fn new(value: T) -> Lock<T> {
+            Lock(Mutex::new(value))
+        }
+
+        // NOTE Nothing panics while the lock is held, so it is never poisoned
+        //      by the crate itself.
+        pub(crate) fn lock(&self) -> MutexGuard<'_, T> {
+            self.0.lock().unwrap_or_else(|err| err.into_inner())
+        }
+    }
+
+    // The types that can be sent and shared between threads.
+    pub trait MaybeSync: Send + Sync {}
+
+    impl<T: Send + Sync + ?Sized> MaybeSync for T {}
+}
+
+pub(crate) use imp::{Counter, Flag, Lock, Shared};
+
+// The bound on values that might end up on another thread, e.g. the values
+// that are traced by the cycle collector. It is `Send + Sync` with the `sync`
+// feature, and holds for every type without it.
+pub use imp::MaybeSync;
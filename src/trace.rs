@@ -0,0 +1,545 @@
+use std::collections::{HashMap, HashSet};
+use std::ptr::NonNull;
+
+use crate::dom::{Header, CLAIMED, DEAD};
+use crate::{Dom, WeakDom};
+
+// Reference counting can't free values that are part of a reference cycle.
+// The tree itself only uses weak back-references, but cycles are still easy to
+// create through data that is attached to the tree, e.g. an event listener
+// that holds on to the element it listens to.
+//
+// Values that are created with `Dom::new_traced` take part in cycle
+// collection. Whenever the strong count of such a value is decreased without
+// reaching zero, the value might have become garbage that is only kept alive
+// by a cycle, so it is buffered as a candidate root.
+//
+// `collect_cycles` then finds all traced values that are reachable from the
+// candidates and counts how many of their strong references that come from
+// within that subgraph. A value that has more strong references than that is
+// referenced from the outside, which keeps it and everything it references
+// alive. The remaining values are garbage, and are dropped and freed.
+//
+// The collector holds a strong reference to each value in the subgraph, and
+// keeps them from being mutably borrowed while it works, so that the values
+// and the references between them stay put. With the `sync` feature other
+// threads can still clone and drop handles in the meantime, so the garbage is
+// claimed before it is dropped: the strong count of each value is checked to
+// be exactly what the subgraph accounts for, and no new handles to it can be
+// created from then on. If another thread got in the way, the values are left
+// for the next collection.
+//
+// The candidates are kept per thread, since a `Dom<T>` can't be sent to
+// another thread, unless the `sync` feature is enabled in which case they are
+// global.
+
+/// Implemented for values that hold `Dom<T>` handles, so that the cycle
+/// collector can find the references between values.
+///
+/// # Safety
+///
+/// `trace` MUST only visit handles that are owned by the value, i.e. handles
+/// that will be dropped when the value is dropped. Visiting a handle that
+/// isn't owned makes its value look like garbage, which might free it while it
+/// is still in use. Missing a handle is safe, but cycles through it will never
+/// be collected.
+///
+/// Dropping a value MUST NOT store a handle to another traced value somewhere
+/// it outlives the drop, since that value might be garbage that is about to be
+/// freed.
+///
+/// With the `sync` feature, the visited handles MUST only be changed while the
+/// value is mutably borrowed through `Dom::borrow_mut`, unless the value is
+/// only ever used from a single thread. The collector relies on the handles
+/// staying the same while it keeps the value from being mutably borrowed.
+pub unsafe trait Trace {
+    fn trace(&self, tracer: &mut Tracer);
+}
+
+// Collects the references of a value while it is being traced.
+pub struct Tracer {
+    edges: Vec<NonNull<Header>>,
+}
+
+impl Tracer {
+    pub fn visit<T>(&mut self, dom: &Dom<T>) {
+        let header = dom.header();
+        let meta = unsafe { header.as_ref() };
+
+        // Values that aren't traced can't be part of a cycle that we are able
+        // to find, so they are left to the reference counting.
+        if meta.traced() {
+            // The collector holds on to the value, since the handle might be
+            // dropped as soon as it stops scanning the value that owns it.
+            meta.increase_strong();
+            self.edges.push(header);
+        }
+    }
+}
+
+// A buffered value. It is only touched while it is known to be alive.
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+struct Candidate(NonNull<Header>);
+
+#[cfg(feature = "sync")]
+unsafe impl Send for Candidate {}
+
+#[cfg(not(feature = "sync"))]
+mod candidates {
+    use std::cell::RefCell;
+    use std::collections::HashSet;
+
+    use super::Candidate;
+    use crate::sync::Flag;
+
+    thread_local! {
+        static CANDIDATES: RefCell<HashSet<Candidate>> = RefCell::new(HashSet::new());
+        static COLLECTING: Flag = const { Flag::new(false) };
+    }
+
+    // Returns `None` if the thread local is gone, which happens if a value is
+    // dropped during thread teardown. There won't be any more collections on
+    // the thread by then anyway.
+    pub(super) fn with<R>(f: impl FnOnce(&mut HashSet<Candidate>) -> R) -> Option<R> {
+        CANDIDATES.try_with(|candidates| f(&mut candidates.borrow_mut())).ok()
+    }
+
+    // Returns if a collection was already running.
+    pub(super) fn start_collecting() -> bool {
+        COLLECTING.with(|collecting| collecting.replace(true))
+    }
+
+    pub(super) fn stop_collecting() {
+        COLLECTING.with(|collecting| collecting.set(false));
+    }
+}
+
+#[cfg(feature = "sync")]
+mod candidates {
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+
+    use once_cell::sync::Lazy;
+
+    use super::Candidate;
+    use crate::sync::Flag;
+
+    static CANDIDATES: Lazy<Mutex<HashSet<Candidate>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+    static COLLECTING: Flag = Flag::new(false);
+
+    pub(super) fn with<R>(f: impl FnOnce(&mut HashSet<Candidate>) -> R) -> Option<R> {
+        // A panic while the lock is held can't leave the set half updated.
+        let mut candidates = CANDIDATES.lock().unwrap_or_else(|err| err.into_inner());
+        Some(f(&mut candidates))
+    }
+
+    // Returns if a collection was already running, on any thread.
+    pub(super) fn start_collecting() -> bool {
+        COLLECTING.replace(true)
+    }
+
+    pub(super) fn stop_collecting() {
+        COLLECTING.set(false);
+    }
+}
+
+// Buffers a traced value as a candidate root, unless it already is one.
+// The caller MUST hold a strong reference to the value.
+pub(crate) fn buffer(header: NonNull<Header>) {
+    let meta = unsafe { header.as_ref() };
+
+    if meta.buffered() {
+        return;
+    }
+
+    // NOTE The flag is only changed while the candidates are locked.
+    let buffered = candidates::with(|candidates| {
+        if !meta.buffered() {
+            candidates.insert(Candidate(header));
+            meta.set_buffered(true);
+        }
+    });
+
+    debug_assert!(buffered.is_some() || !meta.buffered());
+}
+
+// Removes a value from the candidate roots, e.g. because it is freed.
+//
+// NOTE When the value is about to be freed, this MUST be called after its
+//      strong count reached zero. The collector only clears the flag after it
+//      has tried to hold on to the value, so either the flag is clear and the
+//      collector is done with the value, or we wait for the collector here.
+pub(crate) fn unbuffer(header: NonNull<Header>) {
+    let meta = unsafe { header.as_ref() };
+
+    if !meta.buffered() {
+        return;
+    }
+
+    candidates::with(|candidates| {
+        candidates.remove(&Candidate(header));
+        meta.set_buffered(false);
+    });
+}
+
+// Returns the traced values that are directly referenced by the value,
+// each of which the collector now holds a strong reference to.
+fn edges(header: NonNull<Header>) -> Vec<NonNull<Header>> {
+    let mut tracer = Tracer {
+        edges: Vec::new(),
+    };
+
+    unsafe { Header::trace(header, &mut tracer); }
+
+    tracer.edges
+}
+
+// The subgraph of traced values that the collector looks at.
+struct Graph {
+    // The values that the collector holds, and how many strong references it
+    // holds to each of them.
+    holds: HashMap<NonNull<Header>, usize>,
+    // The references from each value to other traced values, or `None` if the
+    // value was mutably borrowed and couldn't be scanned.
+    edges: HashMap<NonNull<Header>, Option<Vec<NonNull<Header>>>>,
+}
+
+impl Graph {
+    // Stops scanning the values and gives up the held references.
+    fn release(self) {
+        for (header, edges) in &self.edges {
+            if edges.is_some() {
+                unsafe { header.as_ref() }.release_scan();
+            }
+        }
+
+        for (header, holds) in self.holds {
+            for _ in 0..holds {
+                unsafe { Header::release(header); }
+            }
+        }
+    }
+}
+
+// Frees all traced values that are only kept alive by reference cycles and
+// returns how many values that were freed.
+pub fn collect_cycles() -> usize {
+    // Dropping the garbage runs arbitrary destructors, which might try to
+    // collect cycles themselves.
+    if candidates::start_collecting() {
+        return 0;
+    }
+
+    let freed = collect();
+
+    candidates::stop_collecting();
+
+    freed
+}
+
+fn collect() -> usize {
+    let mut graph = Graph {
+        holds: HashMap::new(),
+        edges: HashMap::new(),
+    };
+
+    // The roots have to be held before they are unbuffered,
+    // see `unbuffer`.
+    candidates::with(|candidates| {
+        for Candidate(header) in candidates.drain() {
+            let meta = unsafe { header.as_ref() };
+
+            if meta.try_increase_strong() {
+                graph.holds.insert(header, 1);
+            }
+
+            meta.set_buffered(false);
+        }
+    });
+
+    // Find the subgraph of traced values that are reachable from the roots.
+    let mut stack: Vec<NonNull<Header>> = graph.holds.keys().copied().collect();
+
+    while let Some(header) = stack.pop() {
+        if graph.edges.contains_key(&header) {
+            continue;
+        }
+
+        // A value that is mutably borrowed can't be scanned. It is obviously
+        // in use, and whatever it references will look referenced from the
+        // outside since its references aren't subtracted below.
+        let edges = if unsafe { header.as_ref() }.try_scan() {
+            let edges = edges(header);
+
+            for &edge in &edges {
+                *graph.holds.entry(edge).or_insert(0) += 1;
+            }
+
+            stack.extend(edges.iter().copied());
+            Some(edges)
+        } else {
+            None
+        };
+
+        graph.edges.insert(header, edges);
+    }
+
+    // The strong references to each value that are accounted for by the
+    // subgraph, i.e. those held by the collector or by other values in it.
+    let mut internal: HashMap<NonNull<Header>, usize> = graph.holds.clone();
+
+    for edges in graph.edges.values().flatten() {
+        for edge in edges {
+            *internal.get_mut(edge).unwrap() += 1;
+        }
+    }
+
+    // Everything that is reachable from a value that is referenced from the
+    // outside is alive. A mutably borrowed value counts as referenced from
+    // the outside.
+    let mut alive: HashSet<NonNull<Header>> = HashSet::new();
+    let mut stack: Vec<NonNull<Header>> = graph.edges.iter()
+        .filter(|(header, edges)| {
+            edges.is_none() || unsafe { header.as_ref() }.strong() > internal[header]
+        })
+        .map(|(&header, _)| header)
+        .collect();
+
+    while let Some(header) = stack.pop() {
+        if alive.insert(header) {
+            stack.extend(graph.edges[&header].iter().flatten().copied());
+        }
+    }
+
+    let garbage: Vec<NonNull<Header>> = graph.edges.keys()
+        .filter(|header| !alive.contains(header))
+        .copied()
+        .collect();
+
+    // Claim the garbage, which fails if another thread has created or dropped
+    // a handle to it since the strong counts were read above.
+    for (claimed, header) in garbage.iter().enumerate() {
+        let strong = unsafe { header.as_ref() }.strong_raw();
+        let expected = internal[header];
+
+        if strong.compare_exchange(expected, expected | CLAIMED).is_err() {
+            for header in &garbage[..claimed] {
+                unsafe { header.as_ref() }.strong_raw().fetch_and(!CLAIMED);
+            }
+
+            graph.release();
+            return 0;
+        }
+    }
+
+    // Nothing outside of the garbage references it anymore, and no new
+    // handles to it can be created, so it is dead.
+    for header in &garbage {
+        let strong = unsafe { header.as_ref() }.strong_raw();
+        let expected = internal[header];
+        strong.set(expected | DEAD);
+    }
+
+    // Stop scanning and give up the references to the values that are alive.
+    // The references to the garbage are kept while the values are dropped,
+    // since dropping one value releases its references to the others.
+    let holds: Vec<(NonNull<Header>, usize)> = garbage.iter()
+        .map(|header| (*header, graph.holds.remove(header).unwrap_or(0)))
+        .collect();
+
+    graph.release();
+
+    for &header in &garbage {
+        unsafe { Header::drop_value(header); }
+    }
+
+    for (header, holds) in holds {
+        let meta = unsafe { header.as_ref() };
+
+        // Dropping the other values might have buffered this one again.
+        unbuffer(header);
+
+        let strong = meta.strong_raw().get();
+        debug_assert!(strong == DEAD | holds, "A traced value was kept alive by a destructor");
+
+        // The strong references collectively hold a weak reference,
+        // which is released now that there are no strong references left.
+        // If a destructor broke the contract of `Trace` the value is leaked.
+        if strong == DEAD | holds {
+            meta.strong_raw().set(0);
+            unsafe { Header::release_weak(header); }
+        }
+    }
+
+    garbage.len()
+}
+
+unsafe impl<T> Trace for Dom<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.visit(self);
+    }
+}
+
+// Weak handles don't keep values alive, so they can't be part of a cycle.
+unsafe impl<T> Trace for WeakDom<T> {
+    fn trace(&self, _tracer: &mut Tracer) {}
+}
+
+unsafe impl<T: Trace> Trace for Option<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Some(value) = self {
+            value.trace(tracer);
+        }
+    }
+}
+
+unsafe impl<T: Trace> Trace for Vec<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        for value in self {
+            value.trace(tracer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    // A value that can form a cycle and tells when it is dropped.
+    // NOTE A `Mutex` rather than a `RefCell`, so that the value can be traced
+    //      with the `sync` feature as well.
+    struct Link {
+        next: Mutex<Option<Dom<Link>>>,
+        _dropped: DropFlag,
+    }
+
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    unsafe impl Trace for Link {
+        fn trace(&self, tracer: &mut Tracer) {
+            self.next.lock().unwrap().trace(tracer);
+        }
+    }
+
+    struct Dropped(Arc<AtomicBool>);
+
+    impl Dropped {
+        fn get(&self) -> bool {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    fn link() -> (Dom<Link>, Dropped) {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let link = Dom::new_traced(Link {
+            next: Mutex::new(None),
+            _dropped: DropFlag(Arc::clone(&dropped)),
+        });
+
+        (link, Dropped(dropped))
+    }
+
+    #[test]
+    fn collect_self_cycle() {
+        let _lock = crate::tests_init::collector_lock();
+        let (a, a_dropped) = link();
+        *a.borrow().next.lock().unwrap() = Some(Dom::clone(&a));
+        let weak = Dom::downgrade(&a);
+
+        drop(a);
+        assert!(!a_dropped.get());
+
+        assert_eq!(collect_cycles(), 1);
+        assert!(a_dropped.get());
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn collect_cycle_between_values() {
+        let _lock = crate::tests_init::collector_lock();
+        let (a, a_dropped) = link();
+        let (b, b_dropped) = link();
+        *a.borrow().next.lock().unwrap() = Some(Dom::clone(&b));
+        *b.borrow().next.lock().unwrap() = Some(Dom::clone(&a));
+
+        drop(a);
+        drop(b);
+
+        assert_eq!(collect_cycles(), 2);
+        assert!(a_dropped.get());
+        assert!(b_dropped.get());
+    }
+
+    #[test]
+    fn externally_referenced_cycle_is_kept() {
+        let _lock = crate::tests_init::collector_lock();
+        let (a, a_dropped) = link();
+        let (b, b_dropped) = link();
+        *a.borrow().next.lock().unwrap() = Some(Dom::clone(&b));
+        *b.borrow().next.lock().unwrap() = Some(Dom::clone(&a));
+
+        drop(b);
+
+        assert_eq!(collect_cycles(), 0);
+        assert!(!a_dropped.get());
+        assert!(!b_dropped.get());
+
+        // Breaking the cycle lets the reference counting free the values.
+        *a.borrow().next.lock().unwrap() = None;
+        assert!(b_dropped.get());
+        drop(a);
+        assert!(a_dropped.get());
+    }
+
+    #[test]
+    fn mutably_borrowed_cycle_is_kept() {
+        let _lock = crate::tests_init::collector_lock();
+        let (a, a_dropped) = link();
+        let (b, b_dropped) = link();
+        *a.borrow().next.lock().unwrap() = Some(Dom::clone(&b));
+        *b.borrow().next.lock().unwrap() = Some(Dom::clone(&a));
+
+        let weak = Dom::downgrade(&a);
+        let a_mut = a.borrow_mut();
+        drop(b);
+
+        assert_eq!(collect_cycles(), 0);
+        assert!(!a_dropped.get());
+        assert!(!b_dropped.get());
+
+        drop(a_mut);
+        drop(a);
+
+        assert_eq!(collect_cycles(), 2);
+        assert!(a_dropped.get());
+        assert!(b_dropped.get());
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn collect_cycle_dropped_on_another_thread() {
+        let _lock = crate::tests_init::collector_lock();
+        let (a, a_dropped) = link();
+        let (b, b_dropped) = link();
+        *a.borrow().next.lock().unwrap() = Some(Dom::clone(&b));
+        *b.borrow().next.lock().unwrap() = Some(Dom::clone(&a));
+
+        std::thread::spawn(move || {
+            drop(a);
+            drop(b);
+        }).join().unwrap();
+
+        assert_eq!(collect_cycles(), 2);
+        assert!(a_dropped.get());
+        assert!(b_dropped.get());
+    }
+}